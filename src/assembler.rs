@@ -0,0 +1,539 @@
+use thiserror::Error;
+use crate::symbols::SymbolTable;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("line {line}, column {column}: {message}")]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, column: usize, message: impl Into<String>) -> Self {
+        AsmError { line, column, message: message.into() }
+    }
+}
+
+/// An assembled program, ready to be written as an `.obj` image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    pub symbols: SymbolTable,
+}
+
+impl Assembly {
+    /// Big-endian origin followed by the program words, the layout
+    /// `VM::read_image` expects.
+    pub fn to_obj_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 * (self.words.len() + 1));
+        bytes.extend_from_slice(&self.origin.to_be_bytes());
+        for word in &self.words {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    column: usize,
+    is_string: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    And,
+    Br(u16), /* nzp bits */
+    Jmp,
+    Ret,
+    Jsr,
+    Jsrr,
+    Ld,
+    Ldi,
+    Ldr,
+    Lea,
+    Not,
+    Rti,
+    St,
+    Sti,
+    Str,
+    Trap,
+    TrapAlias(u16), /* GETC, OUT, ... with their vector */
+    Orig,
+    Fill,
+    Blkw,
+    Stringz,
+    End,
+}
+
+impl Op {
+    fn parse(text: &str) -> Option<Op> {
+        let upper = text.to_ascii_uppercase();
+        let op = match upper.as_str() {
+            "ADD" => Op::Add,
+            "AND" => Op::And,
+            "JMP" => Op::Jmp,
+            "RET" => Op::Ret,
+            "JSR" => Op::Jsr,
+            "JSRR" => Op::Jsrr,
+            "LD" => Op::Ld,
+            "LDI" => Op::Ldi,
+            "LDR" => Op::Ldr,
+            "LEA" => Op::Lea,
+            "NOT" => Op::Not,
+            "RTI" => Op::Rti,
+            "ST" => Op::St,
+            "STI" => Op::Sti,
+            "STR" => Op::Str,
+            "TRAP" => Op::Trap,
+            "GETC" => Op::TrapAlias(0x20),
+            "OUT" => Op::TrapAlias(0x21),
+            "PUTS" => Op::TrapAlias(0x22),
+            "IN" => Op::TrapAlias(0x23),
+            "PUTSP" => Op::TrapAlias(0x24),
+            "HALT" => Op::TrapAlias(0x25),
+            ".ORIG" => Op::Orig,
+            ".FILL" => Op::Fill,
+            ".BLKW" => Op::Blkw,
+            ".STRINGZ" => Op::Stringz,
+            ".END" => Op::End,
+            _ => return parse_branch(&upper).map(Op::Br),
+        };
+        Some(op)
+    }
+
+    fn operand_count(self) -> usize {
+        match self {
+            Op::Add | Op::And | Op::Ldr | Op::Str => 3,
+            Op::Ld | Op::Ldi | Op::Lea | Op::Not | Op::St | Op::Sti => 2,
+            Op::Br(_) | Op::Jmp | Op::Jsr | Op::Jsrr | Op::Trap => 1,
+            Op::Orig | Op::Fill | Op::Blkw | Op::Stringz => 1,
+            Op::Ret | Op::Rti | Op::TrapAlias(_) | Op::End => 0,
+        }
+    }
+}
+
+// BR, BRn, BRz, BRp, BRnz, BRnp, BRzp, BRnzp. A bare BR is unconditional.
+fn parse_branch(upper: &str) -> Option<u16> {
+    let flags = upper.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(0x7);
+    }
+    let mut nzp = 0;
+    let mut last = 0;
+    for c in flags.chars() {
+        let (bit, order) = match c {
+            'N' => (0x4, 1),
+            'Z' => (0x2, 2),
+            'P' => (0x1, 3),
+            _ => return None,
+        };
+        if order <= last {
+            return None;
+        }
+        last = order;
+        nzp |= bit;
+    }
+    Some(nzp)
+}
+
+#[derive(Debug)]
+struct Statement {
+    line: usize,
+    addr: u16,
+    op: Op,
+    operands: Vec<Token>,
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AsmError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() || c == ',' {
+            i += 1;
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            let column = i + 1;
+            let mut value = String::new();
+            i += 1;
+            loop {
+                let Some(&c) = chars.get(i) else {
+                    return Err(AsmError::new(line, column, "unterminated string literal"));
+                };
+                i += 1;
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        let escaped = match chars.get(i) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('0') => '\0',
+                            Some('e') => '\x1b',
+                            Some('\\') => '\\',
+                            Some('"') => '"',
+                            _ => return Err(AsmError::new(line, i, "invalid escape sequence")),
+                        };
+                        value.push(escaped);
+                        i += 1;
+                    }
+                    c => value.push(c),
+                }
+            }
+            tokens.push(Token { text: value, column, is_string: true });
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace() && !matches!(chars[i], ',' | ';' | '"') {
+                i += 1;
+            }
+            tokens.push(Token {
+                text: chars[start..i].iter().collect(),
+                column: start + 1,
+                is_string: false,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses `#10`, `#-3`, `x3000`, `x-1` and plain decimal literals.
+fn parse_number(text: &str) -> Option<i32> {
+    let (digits, radix) = if let Some(rest) = text.strip_prefix('#') {
+        (rest, 10)
+    } else if let Some(rest) = text.strip_prefix(['x', 'X']) {
+        (rest, 16)
+    } else if text.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        (text, 10)
+    } else {
+        return None;
+    };
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, digits),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u16> {
+    let bytes = text.as_bytes();
+    if bytes.len() == 2 && (bytes[0] == b'R' || bytes[0] == b'r') && (b'0'..=b'7').contains(&bytes[1]) {
+        Some((bytes[1] - b'0') as u16)
+    } else {
+        None
+    }
+}
+
+fn is_valid_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && parse_register(text).is_none()
+}
+
+/// Assembles LC-3 source into an object image and its symbol table.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut symbols = SymbolTable::new();
+    let mut statements = Vec::new();
+    let mut origin = None;
+    let mut addr: u32 = 0;
+    let mut ended = false;
+    let mut last_line = 1;
+
+    // First pass: split lines into statements and assign addresses.
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        last_line = line;
+        let mut tokens = tokenize(text, line)?.into_iter();
+        let Some(first) = tokens.next() else {
+            continue;
+        };
+
+        let (label, op_token) = match Op::parse(&first.text) {
+            Some(_) if !first.is_string => (None, Some(first)),
+            _ => (Some(first), tokens.next()),
+        };
+
+        if let Some(label) = &label {
+            if label.is_string || !is_valid_label(&label.text) {
+                return Err(AsmError::new(line, label.column, format!("invalid label `{}`", label.text)));
+            }
+            if origin.is_none() {
+                return Err(AsmError::new(line, label.column, "label before .ORIG"));
+            }
+            if symbols.address_of(&label.text).is_some() {
+                return Err(AsmError::new(line, label.column, format!("duplicate label `{}`", label.text)));
+            }
+            if addr > 0xFFFF {
+                return Err(AsmError::new(line, label.column, "program does not fit in memory"));
+            }
+            symbols.insert(&label.text, addr as u16);
+        }
+
+        let Some(op_token) = op_token else {
+            continue;
+        };
+        let op = match Op::parse(&op_token.text) {
+            Some(op) if !op_token.is_string => op,
+            _ => {
+                return Err(AsmError::new(line, op_token.column, format!("unknown instruction `{}`", op_token.text)));
+            }
+        };
+
+        let operands: Vec<Token> = tokens.collect();
+        if operands.len() != op.operand_count() {
+            return Err(AsmError::new(
+                line,
+                op_token.column,
+                format!(
+                    "{} expects {} operand(s), found {}",
+                    op_token.text.to_ascii_uppercase(),
+                    op.operand_count(),
+                    operands.len()
+                ),
+            ));
+        }
+
+        match (op, origin) {
+            (Op::Orig, None) => {
+                let value = expect_number(line, &operands[0])?;
+                if !(0..=0xFFFF).contains(&value) {
+                    return Err(AsmError::new(line, operands[0].column, ".ORIG address out of range"));
+                }
+                origin = Some(value as u16);
+                addr = value as u32;
+                continue;
+            }
+            (Op::Orig, Some(_)) => {
+                return Err(AsmError::new(line, op_token.column, "only one .ORIG block is supported"));
+            }
+            (_, None) => {
+                return Err(AsmError::new(line, op_token.column, "expected .ORIG before the first instruction"));
+            }
+            (Op::End, _) => {
+                ended = true;
+                break;
+            }
+            _ => {}
+        }
+
+        let size = match op {
+            Op::Blkw => {
+                let count = expect_number(line, &operands[0])?;
+                if count < 0 {
+                    return Err(AsmError::new(line, operands[0].column, ".BLKW count must not be negative"));
+                }
+                count as u32
+            }
+            Op::Stringz => {
+                if !operands[0].is_string {
+                    return Err(AsmError::new(line, operands[0].column, "expected a string literal"));
+                }
+                operands[0].text.chars().count() as u32 + 1
+            }
+            _ => 1,
+        };
+        if addr + size > 0x10000 {
+            return Err(AsmError::new(line, op_token.column, "program does not fit in memory"));
+        }
+
+        statements.push(Statement { line, addr: addr as u16, op, operands });
+        addr += size;
+    }
+
+    let Some(origin) = origin else {
+        return Err(AsmError::new(last_line, 1, "missing .ORIG directive"));
+    };
+    if !ended {
+        return Err(AsmError::new(last_line, 1, "missing .END directive"));
+    }
+
+    // Second pass: encode now that every label is known.
+    let mut words = Vec::with_capacity((addr - origin as u32) as usize);
+    for statement in &statements {
+        encode(statement, &symbols, &mut words)?;
+    }
+
+    Ok(Assembly { origin, words, symbols })
+}
+
+fn expect_number(line: usize, token: &Token) -> Result<i32, AsmError> {
+    match parse_number(&token.text) {
+        Some(value) if !token.is_string => Ok(value),
+        _ => Err(AsmError::new(line, token.column, format!("expected a number, found `{}`", token.text))),
+    }
+}
+
+fn expect_register(line: usize, token: &Token) -> Result<u16, AsmError> {
+    match parse_register(&token.text) {
+        Some(reg) if !token.is_string => Ok(reg),
+        _ => Err(AsmError::new(line, token.column, format!("expected a register, found `{}`", token.text))),
+    }
+}
+
+fn check_signed(line: usize, token: &Token, value: i32, bits: u32) -> Result<u16, AsmError> {
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    if value < min || value > max {
+        return Err(AsmError::new(
+            line,
+            token.column,
+            format!("value {} does not fit in a {}-bit signed field", value, bits),
+        ));
+    }
+    Ok((value as u16) & ((1 << bits) - 1))
+}
+
+fn expect_immediate(line: usize, token: &Token, bits: u32) -> Result<u16, AsmError> {
+    let value = expect_number(line, token)?;
+    check_signed(line, token, value, bits)
+}
+
+// A PC-relative operand is either a label or a literal offset.
+fn expect_pc_offset(statement: &Statement, token: &Token, symbols: &SymbolTable, bits: u32) -> Result<u16, AsmError> {
+    let line = statement.line;
+    if token.is_string {
+        return Err(AsmError::new(line, token.column, "expected a label or offset"));
+    }
+    if let Some(value) = parse_number(&token.text) {
+        return check_signed(line, token, value, bits);
+    }
+    let Some(target) = symbols.address_of(&token.text) else {
+        return Err(AsmError::new(line, token.column, format!("undefined label `{}`", token.text)));
+    };
+    let offset = target as i32 - (statement.addr as i32 + 1);
+    check_signed(line, token, offset, bits).map_err(|_| {
+        AsmError::new(line, token.column, format!("label `{}` is out of range for a {}-bit offset", token.text, bits))
+    })
+}
+
+fn encode(statement: &Statement, symbols: &SymbolTable, words: &mut Vec<u16>) -> Result<(), AsmError> {
+    let line = statement.line;
+    let ops = &statement.operands;
+    let word = match statement.op {
+        Op::Add | Op::And => {
+            let opcode = if statement.op == Op::Add { 0x1000 } else { 0x5000 };
+            let dr = expect_register(line, &ops[0])?;
+            let sr1 = expect_register(line, &ops[1])?;
+            let last = match parse_register(&ops[2].text) {
+                Some(sr2) => sr2,
+                None => 0x20 | expect_immediate(line, &ops[2], 5)?,
+            };
+            opcode | dr << 9 | sr1 << 6 | last
+        }
+        Op::Br(nzp) => nzp << 9 | expect_pc_offset(statement, &ops[0], symbols, 9)?,
+        Op::Jmp => 0xC000 | expect_register(line, &ops[0])? << 6,
+        Op::Ret => 0xC1C0,
+        Op::Jsr => 0x4800 | expect_pc_offset(statement, &ops[0], symbols, 11)?,
+        Op::Jsrr => 0x4000 | expect_register(line, &ops[0])? << 6,
+        Op::Ld | Op::Ldi | Op::Lea | Op::St | Op::Sti => {
+            let opcode = match statement.op {
+                Op::Ld => 0x2000,
+                Op::Ldi => 0xA000,
+                Op::Lea => 0xE000,
+                Op::St => 0x3000,
+                _ => 0xB000,
+            };
+            let reg = expect_register(line, &ops[0])?;
+            opcode | reg << 9 | expect_pc_offset(statement, &ops[1], symbols, 9)?
+        }
+        Op::Ldr | Op::Str => {
+            let opcode = if statement.op == Op::Ldr { 0x6000 } else { 0x7000 };
+            let reg = expect_register(line, &ops[0])?;
+            let base = expect_register(line, &ops[1])?;
+            opcode | reg << 9 | base << 6 | expect_immediate(line, &ops[2], 6)?
+        }
+        Op::Not => {
+            let dr = expect_register(line, &ops[0])?;
+            let sr = expect_register(line, &ops[1])?;
+            0x903F | dr << 9 | sr << 6
+        }
+        Op::Rti => 0x8000,
+        Op::Trap => {
+            let vector = expect_number(line, &ops[0])?;
+            if !(0..=0xFF).contains(&vector) {
+                return Err(AsmError::new(line, ops[0].column, "trap vector must fit in 8 bits"));
+            }
+            0xF000 | vector as u16
+        }
+        Op::TrapAlias(vector) => 0xF000 | vector,
+        Op::Fill => {
+            let token = &ops[0];
+            match parse_number(&token.text) {
+                Some(value) if !token.is_string => {
+                    if !(-0x8000..=0xFFFF).contains(&value) {
+                        return Err(AsmError::new(line, token.column, ".FILL value does not fit in 16 bits"));
+                    }
+                    value as u16
+                }
+                _ => symbols.address_of(&token.text).ok_or_else(|| {
+                    AsmError::new(line, token.column, format!("undefined label `{}`", token.text))
+                })?,
+            }
+        }
+        Op::Blkw => {
+            let count = expect_number(line, &ops[0])? as usize;
+            words.extend(std::iter::repeat_n(0, count));
+            return Ok(());
+        }
+        Op::Stringz => {
+            for c in ops[0].text.chars() {
+                if c as u32 > 0xFF {
+                    return Err(AsmError::new(line, ops[0].column, format!("character `{}` is not 8-bit", c)));
+                }
+                words.push(c as u16);
+            }
+            0
+        }
+        Op::Orig | Op::End => unreachable!("directives are consumed by the first pass"),
+    };
+    words.push(word);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_encodes_instructions_and_labels() {
+        let source = "\
+            .ORIG x3000
+            LOOP  ADD R1, R1, #-1   ; count down
+                  BRp LOOP
+                  LEA R0, MSG
+                  PUTS
+                  HALT
+            MSG   .STRINGZ \"hi\"
+            .END";
+        let assembly = assemble(source).unwrap();
+
+        assert_eq!(assembly.origin, 0x3000);
+        assert_eq!(assembly.words, vec![0x127F, 0x03FE, 0xE002, 0xF022, 0xF025, 0x68, 0x69, 0]);
+        assert_eq!(assembly.symbols.address_of("LOOP"), Some(0x3000));
+        assert_eq!(assembly.symbols.address_of("MSG"), Some(0x3005));
+        assert_eq!(&assembly.to_obj_bytes()[..4], &[0x30, 0x00, 0x12, 0x7F]);
+    }
+
+    #[test]
+    fn test_assemble_reports_line_and_column() {
+        let source = ".ORIG x3000\nADD R0, R0, #99\n.END\n";
+        let err = assemble(source).unwrap_err();
+
+        assert_eq!((err.line, err.column), (2, 13));
+
+        let err = assemble(".ORIG x3000\n  BRz NOWHERE\n.END").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 7));
+        assert!(err.message.contains("NOWHERE"));
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+use std::fmt;
+use crate::symbols::SymbolTable;
+use crate::vm::Opcodes;
+
+/// Deepest call stack kept; past this the outermost frames are dropped,
+/// e.g. under runaway recursion.
+pub const MAX_DEPTH: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Subroutine, /* JSR or JSRR */
+    Trap(u8),   /* TRAP serviced from memory, carries the vector */
+}
+
+/// One active call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub call_site: u16,
+    pub entry: u16, /* first instruction of the callee */
+    pub return_addr: u16,
+    pub kind: CallKind,
+    r7_saved: bool, /* the callee stored or copied R7 */
+    warned: bool,
+}
+
+/// Problems spotted by the stack discipline checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackWarning {
+    /// A nested call at `pc` overwrote R7 before the subroutine at `entry`
+    /// saved its return address.
+    ClobberedReturn { entry: u16, pc: u16 },
+    /// `RET` at `pc` went to `target` rather than where the innermost call
+    /// returns to.
+    BadReturn { pc: u16, target: u16, expected: u16 },
+}
+
+impl fmt::Display for StackWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackWarning::ClobberedReturn { entry, pc } => write!(
+                f,
+                "call at x{:04X} overwrites R7 before the subroutine at x{:04X} saved its return address",
+                pc, entry
+            ),
+            StackWarning::BadReturn { pc, target, expected } => write!(
+                f,
+                "RET at x{:04X} went to x{:04X}, the innermost call returns to x{:04X}",
+                pc, target, expected
+            ),
+        }
+    }
+}
+
+/// Shadow call stack, kept by watching JSR/JSRR, `RET` (`JMP R7`) and
+/// TRAPs serviced from memory. LC-3 has no hardware stack, so this is what
+/// the program meant to do, not what is in memory.
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: VecDeque<Frame>,
+    warnings: Vec<StackWarning>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Active calls, outermost first.
+    pub fn frames(&self) -> impl DoubleEndedIterator<Item = &Frame> {
+        self.frames.iter()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Warnings raised since the last call.
+    pub fn take_warnings(&mut self) -> Vec<StackWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    pub fn backtrace(&self, pc: u16) -> StackTrace {
+        StackTrace { pc, frames: self.frames.iter().cloned().collect() }
+    }
+
+    /// Observes an instruction that retired at `pc`, leaving the PC at
+    /// `next_pc`.
+    pub fn retire(&mut self, pc: u16, instruction: u16, next_pc: u16, traps_in_memory: bool) {
+        if !Self::affected_by(instruction) {
+            return;
+        }
+        match Opcodes::try_from(instruction >> 12) {
+            Ok(Opcodes::JSR) => self.call(pc, next_pc, CallKind::Subroutine),
+            Ok(Opcodes::TRAP) if traps_in_memory => self.call(pc, next_pc, CallKind::Trap(instruction as u8)),
+            Ok(Opcodes::TRAP) => self.check_saved(pc),
+            Ok(Opcodes::JMP) => self.ret(pc, next_pc),
+            _ => self.mark_saved(),
+        }
+    }
+
+    /// Whether `retire` could change the frames for this instruction.
+    pub fn affected_by(instruction: u16) -> bool {
+        let sr = (instruction >> 9) & 0x7;
+        let base = (instruction >> 6) & 0x7;
+        match Opcodes::try_from(instruction >> 12) {
+            Ok(Opcodes::JSR | Opcodes::TRAP) => true,
+            Ok(Opcodes::JMP) => base == 7,
+            // Saving R7 means storing it, or copying it to another register.
+            Ok(Opcodes::ST | Opcodes::STI | Opcodes::STR) => sr == 7,
+            Ok(Opcodes::ADD | Opcodes::AND) => base == 7,
+            _ => false,
+        }
+    }
+
+    /// Copy of the frames, for `restore_frames`.
+    pub fn save_frames(&self) -> Vec<Frame> {
+        self.frames.iter().cloned().collect()
+    }
+
+    /// Puts back frames saved earlier, e.g. when stepping backwards.
+    /// Warnings are kept.
+    pub fn restore_frames(&mut self, frames: Vec<Frame>) {
+        self.frames = frames.into();
+    }
+
+    fn call(&mut self, pc: u16, entry: u16, kind: CallKind) {
+        self.check_saved(pc);
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.pop_front();
+        }
+        let return_addr = pc.wrapping_add(1);
+        self.frames.push_back(Frame { call_site: pc, entry, return_addr, kind, r7_saved: false, warned: false });
+    }
+
+    // Returns to the innermost matching call. Returns from code that was
+    // not called (e.g. the program's entry) are ignored.
+    fn ret(&mut self, pc: u16, target: u16) {
+        let Some(top) = self.frames.back() else {
+            return;
+        };
+        if top.return_addr != target {
+            self.warnings.push(StackWarning::BadReturn { pc, target, expected: top.return_addr });
+        }
+        if let Some(depth) = self.frames.iter().rposition(|frame| frame.return_addr == target) {
+            self.frames.truncate(depth);
+        }
+    }
+
+    fn check_saved(&mut self, pc: u16) {
+        if let Some(top) = self.frames.back_mut()
+            && !top.r7_saved
+            && !top.warned
+        {
+            top.warned = true;
+            self.warnings.push(StackWarning::ClobberedReturn { entry: top.entry, pc });
+        }
+    }
+
+    fn mark_saved(&mut self) {
+        if let Some(top) = self.frames.back_mut() {
+            top.r7_saved = true;
+        }
+    }
+}
+
+/// The call stack at a given PC, innermost frame first when printed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTrace {
+    pub pc: u16,
+    pub frames: Vec<Frame>, /* outermost first */
+}
+
+impl StackTrace {
+    /// One line per level, naming functions by label where `symbols` has
+    /// one:
+    ///
+    /// ```text
+    /// #0  x3012 in PRINT (x3010)
+    /// #1  x3004 in MAIN (x3000)
+    /// #2  x3001
+    /// ```
+    pub fn format(&self, symbols: Option<&SymbolTable>) -> String {
+        let name = |frame: &Frame| {
+            let label = symbols.and_then(|symbols| symbols.name_at(frame.entry));
+            let name = match label {
+                Some(label) => format!("{} (x{:04X})", label, frame.entry),
+                None => format!("x{:04X}", frame.entry),
+            };
+            match frame.kind {
+                CallKind::Subroutine => name,
+                CallKind::Trap(vector) => format!("{} [TRAP x{:02X}]", name, vector),
+            }
+        };
+        let mut lines = Vec::new();
+        let mut pc = self.pc;
+        for frame in self.frames.iter().rev() {
+            lines.push(format!("#{:<2} x{:04X} in {}", lines.len(), pc, name(frame)));
+            pc = frame.call_site;
+        }
+        lines.push(format!("#{:<2} x{:04X}", lines.len(), pc));
+        lines.join("\n")
+    }
+}
+
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{VMError, VM};
+
+    fn tracked(source: &str) -> (VM, SymbolTable) {
+        let (mut vm, symbols) = VM::from_source(source);
+        vm.set_call_stack(Some(CallStack::new()));
+        (vm, symbols)
+    }
+
+    #[test]
+    fn test_backtrace_attached_to_fault() {
+        let (mut vm, symbols) = tracked(
+            ".ORIG x3000\nJSR OUTER\nHALT\nOUTER ST R7, SAVE\nJSR INNER\nLD R7, SAVE\nRET\n\
+             INNER .FILL xD000\nRET\nSAVE .BLKW 1\n.END",
+        );
+        let Err(VMError::Fault { error, stack }) = vm.run() else { panic!("expected a fault") };
+        assert!(matches!(*error, VMError::ReservedOpcode { pc: 0x3006, .. }));
+        assert_eq!(
+            stack.format(Some(&symbols)),
+            "#0  x3006 in INNER (x3006)\n#1  x3003 in OUTER (x3002)\n#2  x3000"
+        );
+        assert!(vm.call_stack_mut().unwrap().take_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_discipline_warnings() {
+        let (mut vm, _) = tracked(
+            ".ORIG x3000\nJSR OUTER\nHALT\nOUTER JSR INNER\nHALT\nHALT\nINNER ADD R7, R7, #1\nRET\n.END",
+        );
+        vm.run().unwrap();
+        let calls = vm.call_stack_mut().unwrap();
+        assert_eq!(
+            calls.take_warnings(),
+            vec![
+                StackWarning::ClobberedReturn { entry: 0x3002, pc: 0x3002 },
+                StackWarning::BadReturn { pc: 0x3006, target: 0x3004, expected: 0x3003 },
+            ]
+        );
+        // Neither call returned properly.
+        assert_eq!(calls.depth(), 2);
+    }
+}
@@ -0,0 +1,194 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::fd::AsFd;
+use std::rc::Rc;
+use nix::sys::select::{select, FdSet};
+use nix::sys::time::TimeVal;
+
+/// Character device the VM uses for keyboard input and console output.
+///
+/// The trap routines (GETC, IN, OUT, PUTS, PUTSP) and the keyboard
+/// registers go through this trait, so the VM can be driven from a
+/// terminal, a test harness or nothing at all.
+pub trait Console {
+    /// Blocks until a byte is available. Running out of input is reported
+    /// as an `UnexpectedEof` error.
+    fn read_byte(&mut self) -> io::Result<u8>;
+
+    /// Returns a byte only if one is ready right now, never blocks.
+    fn poll_byte(&mut self) -> io::Result<Option<u8>>;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Console backed by the process stdin/stdout.
+#[derive(Default)]
+pub struct TerminalConsole;
+
+impl TerminalConsole {
+    pub fn new() -> Self {
+        TerminalConsole
+    }
+}
+
+fn check_key() -> bool {
+    let stdin = io::stdin();
+
+    let mut readfds = FdSet::new();
+    let binding = stdin.as_fd();
+    readfds.insert(&binding);
+
+    let mut timeout = TimeVal::new(0, 0);
+
+    match select(
+        None,                 // ⚠️ modern nix infers nfds
+        Some(&mut readfds),
+        None,
+        None,
+        Some(&mut timeout),
+    ) {
+        Ok(n) => n > 0,
+        Err(_) => false,
+    }
+}
+
+// Reads the fd directly rather than through `io::stdin()`, whose buffer
+// could hold bytes that `select` no longer reports as ready.
+fn read_stdin_byte() -> io::Result<u8> {
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) } {
+            1 => return Ok(byte),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+impl Console for TerminalConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        read_stdin_byte()
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        if !check_key() {
+            return Ok(None);
+        }
+        self.read_byte().map(Some)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+/// In-memory console for scripted keystrokes and captured output.
+///
+/// Clones share the same buffers, so a harness can keep a handle after
+/// giving the console to the VM.
+#[derive(Clone, Default)]
+pub struct BufferConsole {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        let console = BufferConsole::default();
+        console.push_input(input);
+        console
+    }
+
+    pub fn push_input(&self, input: &[u8]) {
+        self.buffers.borrow_mut().input.extend(input);
+    }
+
+    /// Everything the program has written so far.
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.buffers
+            .borrow_mut()
+            .input
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.buffers.borrow_mut().input.pop_front())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.buffers.borrow_mut().output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Console with no input and discarded output, for headless runs.
+#[derive(Default)]
+pub struct NullConsole;
+
+impl Console for NullConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(None)
+    }
+
+    fn write_bytes(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_console_shares_state_between_clones() {
+        let console = BufferConsole::new(b"ab");
+        let mut handle = console.clone();
+
+        assert_eq!(handle.read_byte().unwrap(), b'a');
+        assert_eq!(handle.read_byte().unwrap(), b'b');
+        assert!(handle.read_byte().is_err());
+
+        handle.write_bytes(b"hi").unwrap();
+        assert_eq!(console.output(), b"hi");
+    }
+}
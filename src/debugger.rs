@@ -0,0 +1,531 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use crate::callstack::CallStack;
+use crate::disassembler;
+use crate::number;
+use crate::snapshot::Snapshot;
+use crate::symbols::SymbolTable;
+use crate::undo::UndoLog;
+use crate::video::Framebuffer;
+use crate::vm::{ConditionFlags, Opcodes, Register, StepOutcome, VMError, VM};
+use crate::watch::{WatchHit, Watchpoint};
+
+const HELP: &str = "\
+break [addr|label]      set a breakpoint, or list them (b)
+delete <addr|label>     clear a breakpoint (d)
+watch <addr>[-<end>] [read|write|change] [if <cond>]
+                        stop on memory accesses, or list watchpoints; the
+                        condition is `value ==|!= <n>` or `pc [not] in <a>-<b>`
+unwatch <id>            remove a watchpoint
+step [n]                execute n instructions (s)
+next                    step over JSR/JSRR/TRAP (n)
+continue                run until a breakpoint or halt (c)
+reverse-step [n]        undo the last n instructions (rs)
+reverse-continue        run backwards to the previous breakpoint (rc)
+last-write <addr|label> find the instruction that last stored to an address
+backtrace               show the active subroutine calls (bt)
+regs                    print registers (r)
+x <addr> [count]        examine memory
+set <addr|reg> <value>  modify memory or a register
+disas [addr] [count]    disassemble, around PC by default (l)
+screenshot <file>       save the video display as PNG, or PPM for .ppm
+save <file>             save a snapshot of the whole machine
+load <file>             restore a snapshot
+quit                    leave the debugger (q)
+An empty line repeats the last command.";
+
+/// Number of instructions that can be stepped back over.
+pub const UNDO_DEPTH: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandResult {
+    Continue,
+    Quit,
+}
+
+/// Why execution stopped after a step/next/continue command.
+enum Stop {
+    Stepped,
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    Halted,
+    Fault,
+}
+
+/// Interactive debugger wrapping a loaded VM.
+pub struct Debugger {
+    vm: VM,
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u16>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(mut vm: VM, symbols: SymbolTable) -> Self {
+        vm.turn_on();
+        if vm.undo_log().is_none() {
+            vm.set_undo_log(Some(UndoLog::new(UNDO_DEPTH)));
+        }
+        if vm.call_stack().is_none() {
+            vm.set_call_stack(Some(CallStack::new()));
+        }
+        Debugger { vm, symbols, breakpoints: BTreeSet::new(), last_command: String::new() }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Reads commands from `input` until `quit` or end of input.
+    pub fn repl(&mut self, mut input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(out, "(lc3db) ")?;
+            out.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            if self.execute(&line, out)? == CommandResult::Quit {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs a single debugger command, writing its output to `out`.
+    pub fn execute(&mut self, line: &str, out: &mut impl Write) -> io::Result<CommandResult> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => {
+                self.last_command = line.to_string();
+                line.to_string()
+            }
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(CommandResult::Continue);
+        };
+
+        match (command, args) {
+            ("break" | "b", []) => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "No breakpoints")?;
+                }
+                for &addr in &self.breakpoints {
+                    writeln!(out, "Breakpoint at {}", self.describe(addr))?;
+                }
+            }
+            ("break" | "b", [target]) => match self.parse_address(target) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    writeln!(out, "Breakpoint set at {}", self.describe(addr))?;
+                }
+                None => writeln!(out, "Unknown address or label `{}`", target)?,
+            },
+            ("delete" | "d", [target]) => match self.parse_address(target) {
+                Some(addr) if self.breakpoints.remove(&addr) => {
+                    writeln!(out, "Breakpoint cleared at {}", self.describe(addr))?
+                }
+                Some(addr) => writeln!(out, "No breakpoint at {}", self.describe(addr))?,
+                None => writeln!(out, "Unknown address or label `{}`", target)?,
+            },
+            ("watch", []) => {
+                if self.vm.watchpoints().is_empty() {
+                    writeln!(out, "No watchpoints")?;
+                }
+                for (id, watchpoint) in self.vm.watchpoints().iter() {
+                    writeln!(out, "Watchpoint {}: {}", id, watchpoint)?;
+                }
+            }
+            ("watch", _) => match Watchpoint::parse(line["watch".len()..].trim(), |text| self.parse_address(text)) {
+                Ok(watchpoint) => {
+                    let description = watchpoint.to_string();
+                    let id = self.vm.watchpoints_mut().add(watchpoint);
+                    writeln!(out, "Watchpoint {}: {}", id, description)?;
+                }
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            ("unwatch", [id]) => match number::parse_number(id) {
+                Some(id) if self.vm.watchpoints_mut().remove(id as usize) => writeln!(out, "Watchpoint {} removed", id)?,
+                _ => writeln!(out, "No watchpoint {}", id)?,
+            },
+            ("step" | "s", []) => self.step(1, out)?,
+            ("step" | "s", [count]) => match parse_count(count) {
+                Ok(count) => self.step(count, out)?,
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            ("next" | "n", []) => self.next(out)?,
+            ("continue" | "c", []) => {
+                let stop = self.resume(None, out)?;
+                self.report(stop, out)?;
+            }
+            ("reverse-step" | "rs", []) => self.step_back(1, out)?,
+            ("reverse-step" | "rs", [count]) => match parse_count(count) {
+                Ok(count) => self.step_back(count, out)?,
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            ("reverse-continue" | "rc", []) => {
+                let breakpoints = &self.breakpoints;
+                if self.vm.reverse_continue(|pc| breakpoints.contains(&pc)) == 0 {
+                    writeln!(out, "No more history")?;
+                }
+                let pc = self.vm.get_pc();
+                if self.breakpoints.contains(&pc) {
+                    writeln!(out, "Breakpoint at {}", self.describe(pc))?;
+                }
+                self.disassemble(pc, 1, out)?;
+            }
+            ("last-write", [target]) => match self.parse_address(target) {
+                Some(addr) => self.last_write(addr, out)?,
+                None => writeln!(out, "Unknown address or label `{}`", target)?,
+            },
+            ("backtrace" | "bt", []) => {
+                let pc = self.vm.get_pc();
+                match self.vm.call_stack() {
+                    Some(calls) => writeln!(out, "{}", calls.backtrace(pc).format(Some(&self.symbols)))?,
+                    None => writeln!(out, "Call stack tracking is off")?,
+                }
+            }
+            ("regs" | "r", []) => self.print_registers(out)?,
+            ("x", [addr]) => self.examine(addr, "8", out)?,
+            ("x", [addr, count]) => self.examine(addr, count, out)?,
+            ("set", [target, value]) => self.set(target, value, out)?,
+            ("disas" | "l", []) => {
+                let pc = self.vm.get_pc();
+                self.disassemble(pc.wrapping_sub(4), 10, out)?;
+            }
+            ("disas" | "l", [addr]) => match self.parse_address(addr) {
+                Some(addr) => self.disassemble(addr, 10, out)?,
+                None => writeln!(out, "Unknown address or label `{}`", addr)?,
+            },
+            ("disas" | "l", [addr, count]) => match (self.parse_address(addr), parse_count(count)) {
+                (Some(addr), Ok(count)) => self.disassemble(addr, count, out)?,
+                (Some(_), Err(e)) => writeln!(out, "{}", e)?,
+                (None, _) => writeln!(out, "Usage: disas [addr] [count]")?,
+            },
+            ("screenshot", [path]) => match self.vm.devices().get::<Framebuffer>() {
+                Some(video) => match video.save(Path::new(path)) {
+                    Ok(()) => writeln!(out, "Saved {}", path)?,
+                    Err(e) => writeln!(out, "Failed to write {}: {}", path, e)?,
+                },
+                None => writeln!(out, "No video device attached")?,
+            },
+            ("save", [path]) => match self.vm.snapshot().save(path) {
+                Ok(()) => writeln!(out, "Snapshot saved to {}", path)?,
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            ("load", [path]) => match Snapshot::load(path).and_then(|snapshot| self.vm.restore(&snapshot)) {
+                Ok(()) => {
+                    writeln!(out, "Snapshot restored from {}", path)?;
+                    let pc = self.vm.get_pc();
+                    self.disassemble(pc, 1, out)?;
+                }
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            ("help" | "h", _) => writeln!(out, "{}", HELP)?,
+            ("quit" | "q", _) => return Ok(CommandResult::Quit),
+            _ => writeln!(out, "Unknown command `{}`, try `help`", line)?,
+        }
+        Ok(CommandResult::Continue)
+    }
+
+    fn step(&mut self, count: u16, out: &mut impl Write) -> io::Result<()> {
+        let mut stop = Stop::Stepped;
+        for _ in 0..count {
+            stop = self.single_step(out)?;
+            if !matches!(stop, Stop::Stepped) {
+                break;
+            }
+        }
+        self.report(stop, out)
+    }
+
+    fn step_back(&mut self, count: u16, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..count {
+            if !self.vm.step_back() {
+                writeln!(out, "No more history")?;
+                break;
+            }
+        }
+        let pc = self.vm.get_pc();
+        self.disassemble(pc, 1, out)
+    }
+
+    fn last_write(&mut self, addr: u16, out: &mut impl Write) -> io::Result<()> {
+        let found = self
+            .vm
+            .undo_log()
+            .and_then(|log| log.last_write_to(addr))
+            .map(|(record, write)| (record.index, record.pc(), *write));
+        let Some((index, pc, write)) = found else {
+            return writeln!(out, "No recorded write to {}", self.describe(addr));
+        };
+        writeln!(
+            out,
+            "{} changed x{:04X} -> x{:04X} at step {}, by:",
+            self.describe(addr),
+            write.old,
+            write.new,
+            index
+        )?;
+        self.disassemble(pc, 1, out)
+    }
+
+    fn next(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.vm.get_pc();
+        let instruction = self.vm.peek(pc);
+        let is_call = matches!(Opcodes::try_from(instruction >> 12), Ok(Opcodes::JSR | Opcodes::TRAP));
+        let stop = if is_call {
+            self.resume(Some(pc.wrapping_add(1)), out)?
+        } else {
+            self.single_step(out)?
+        };
+        self.report(stop, out)
+    }
+
+    // Steps until a breakpoint, halt, fault or the optional return address.
+    fn resume(&mut self, until: Option<u16>, out: &mut impl Write) -> io::Result<Stop> {
+        loop {
+            let stop = self.single_step(out)?;
+            if !matches!(stop, Stop::Stepped) {
+                return Ok(stop);
+            }
+            let pc = self.vm.get_pc();
+            if until == Some(pc) {
+                return Ok(Stop::Stepped);
+            }
+            if self.breakpoints.contains(&pc) {
+                return Ok(Stop::Breakpoint(pc));
+            }
+        }
+    }
+
+    fn single_step(&mut self, out: &mut impl Write) -> io::Result<Stop> {
+        if !self.vm.is_running() {
+            return Ok(Stop::Halted);
+        }
+        match self.vm.step() {
+            Ok(StepOutcome::Halted) => Ok(Stop::Halted),
+            Ok(StepOutcome::Watchpoint(hit)) => Ok(Stop::Watchpoint(hit)),
+            Ok(_) => Ok(Stop::Stepped),
+            Err(VMError::Fault { error, stack }) => {
+                writeln!(out, "Fault: {}", error)?;
+                writeln!(out, "{}", stack.format(Some(&self.symbols)))?;
+                Ok(Stop::Fault)
+            }
+            Err(e) => {
+                writeln!(out, "Fault: {}", e)?;
+                Ok(Stop::Fault)
+            }
+        }
+    }
+
+    fn report(&mut self, stop: Stop, out: &mut impl Write) -> io::Result<()> {
+        let warnings = self.vm.call_stack_mut().map(CallStack::take_warnings).unwrap_or_default();
+        for warning in warnings {
+            writeln!(out, "Warning: {}", warning)?;
+        }
+        match stop {
+            Stop::Halted => return writeln!(out, "Program halted"),
+            Stop::Breakpoint(addr) => writeln!(out, "Breakpoint at {}", self.describe(addr))?,
+            Stop::Watchpoint(hit) => writeln!(out, "{}", hit)?,
+            Stop::Stepped | Stop::Fault => {}
+        }
+        let pc = self.vm.get_pc();
+        self.disassemble(pc, 1, out)
+    }
+
+    fn print_registers(&mut self, out: &mut impl Write) -> io::Result<()> {
+        for row in [0..4, 4..8] {
+            let regs: Vec<String> = row.map(|r| format!("R{} x{:04X}", r, self.vm.read_reg(r))).collect();
+            writeln!(out, "{}", regs.join("  "))?;
+        }
+        let cond = self.vm.read_reg(Register::COND as usize);
+        let flag = ConditionFlags::try_from(cond).map(|flag| flag.name()).unwrap_or("?");
+        writeln!(out, "PC x{:04X}  COND x{:04X} ({})", self.vm.get_pc(), cond, flag)?;
+        let mode = if self.vm.is_supervisor() { "supervisor" } else { "user" };
+        writeln!(
+            out,
+            "PSR x{:04X} ({}, priority {})  USP x{:04X}  SSP x{:04X}",
+            self.vm.get_psr(),
+            mode,
+            self.vm.priority(),
+            self.vm.saved_usp(),
+            self.vm.saved_ssp()
+        )
+    }
+
+    fn examine(&mut self, addr: &str, count: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(start) = self.parse_address(addr) else {
+            return writeln!(out, "Usage: x <addr> [count]");
+        };
+        let count = match parse_count(count) {
+            Ok(count) => u32::from(count),
+            Err(e) => return writeln!(out, "{}", e),
+        };
+        for row in (0..count).step_by(8) {
+            let row_start = start.wrapping_add(row as u16);
+            let words: Vec<String> = (row..count.min(row + 8))
+                .map(|i| format!("x{:04X}", self.vm.peek(start.wrapping_add(i as u16))))
+                .collect();
+            writeln!(out, "x{:04X}: {}", row_start, words.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, target: &str, value: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(value) = number::parse_value(value) else {
+            return writeln!(out, "Invalid value `{}`", value);
+        };
+        if let Some(reg) = parse_register(target) {
+            self.vm.set_reg(reg, value);
+            return writeln!(out, "{} = x{:04X}", target.to_ascii_uppercase(), value);
+        }
+        match self.parse_address(target) {
+            Some(addr) if self.vm.poke(addr, value) => writeln!(out, "x{:04X} = x{:04X}", addr, value),
+            Some(addr) => writeln!(out, "x{:04X} is a device register and can't be set", addr),
+            None => writeln!(out, "Unknown address, label or register `{}`", target),
+        }
+    }
+
+    fn disassemble(&mut self, start: u16, count: u16, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.vm.get_pc();
+        for line in disassembler::disassemble_memory(&self.vm, start, count, Some(&self.symbols)) {
+            let marker = if line.addr == pc { "=>" } else if self.breakpoints.contains(&line.addr) { " *" } else { "  " };
+            writeln!(out, "{} {}", marker, line)?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self, addr: u16) -> String {
+        match self.symbols.name_at(addr) {
+            Some(name) => format!("x{:04X} ({})", addr, name),
+            None => format!("x{:04X}", addr),
+        }
+    }
+
+    fn parse_address(&self, text: &str) -> Option<u16> {
+        self.symbols
+            .address_of(text)
+            .or_else(|| number::parse_word(text))
+    }
+}
+
+// Repeat counts are capped at the size of memory, so a typo can't run
+// (or print) practically forever.
+fn parse_count(text: &str) -> Result<u16, String> {
+    match number::parse_number(text) {
+        Some(count) => u16::try_from(count).map_err(|_| "Count must be at most xFFFF".to_string()),
+        None => Err(format!("Invalid count `{}`", text)),
+    }
+}
+
+fn parse_register(text: &str) -> Option<usize> {
+    match text.to_ascii_uppercase().as_str() {
+        "PC" => Some(Register::PC as usize),
+        "COND" => Some(Register::COND as usize),
+        reg => reg.strip_prefix('R')?.parse().ok().filter(|&r| r < 8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debugger(source: &str) -> Debugger {
+        let (vm, symbols) = VM::from_source(source);
+        Debugger::new(vm, symbols)
+    }
+
+    fn run(debugger: &mut Debugger, command: &str) -> String {
+        let mut out = Vec::new();
+        debugger.execute(command, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoint_by_label_and_continue() {
+        let mut dbg = debugger(".ORIG x3000\nAND R0, R0, #0\nADD R0, R0, #2\nDONE HALT\n.END");
+
+        assert!(run(&mut dbg, "break DONE").contains("x3002 (DONE)"));
+        assert!(run(&mut dbg, "continue").contains("Breakpoint at x3002 (DONE)"));
+        assert!(run(&mut dbg, "regs").contains("R0 x0002"));
+        assert!(run(&mut dbg, "regs").contains("COND x0001 (P)"));
+        assert!(run(&mut dbg, "c").contains("Program halted"));
+    }
+
+    #[test]
+    fn test_next_steps_over_subroutine_and_memory_edit() {
+        let mut dbg = debugger(".ORIG x3000\nJSR SUB\nHALT\nSUB ADD R1, R1, #1\nRET\n.END");
+
+        assert!(run(&mut dbg, "next").contains("=> x3001"));
+        assert_eq!(dbg.vm_mut().read_reg(1), 1);
+
+        run(&mut dbg, "set x4000 x1234");
+        assert!(run(&mut dbg, "x x4000 2").contains("x4000: x1234 x0000"));
+        assert!(dbg.vm_mut().undo_log().unwrap().last_write_to(0x4000).is_none());
+        assert!(run(&mut dbg, "set xFE06 x41").contains("device register"));
+        assert!(run(&mut dbg, "disas x3000 65536").contains("at most xFFFF"));
+        assert!(run(&mut dbg, "x 0 4000000000").contains("at most xFFFF"));
+        assert!(run(&mut dbg, "step 4000000000").contains("at most xFFFF"));
+        assert!(run(&mut dbg, "rs x10000").contains("at most xFFFF"));
+    }
+
+    #[test]
+    fn test_watchpoint_stops_continue() {
+        let mut dbg = debugger(
+            ".ORIG x3000\nLEA R1, BUF\nLOOP STR R0, R1, #0\nADD R1, R1, #1\nADD R0, R0, #1\nBRnzp LOOP\nBUF .BLKW 2\nPAST .FILL #0\n.END",
+        );
+
+        assert!(run(&mut dbg, "watch PAST change if pc in x3000-x3004").contains("Watchpoint 1: x3007 change if pc in x3000-x3004"));
+        let stop = run(&mut dbg, "c");
+        assert!(stop.contains("Watchpoint 1: store to x3007, x0000 -> x0002, at PC x3001"), "{}", stop);
+        assert!(stop.contains("=> x3002"));
+        assert!(run(&mut dbg, "watch").contains("Watchpoint 1: x3007 change"));
+        assert!(run(&mut dbg, "unwatch 1").contains("removed"));
+        assert!(run(&mut dbg, "watch NOWHERE").contains("unknown address or label `NOWHERE`"));
+    }
+
+    #[test]
+    fn test_backtrace_and_stack_warnings() {
+        let mut dbg = debugger(
+            ".ORIG x3000\nJSR PRINT\nHALT\nPRINT LD R0, CHAR\nJSR EMIT\nRET\nEMIT OUT\nRET\nCHAR .FILL x41\n.END",
+        );
+
+        run(&mut dbg, "break EMIT");
+        let stop = run(&mut dbg, "c");
+        assert!(stop.contains("Warning: call at x3003 overwrites R7 before the subroutine at x3002 saved"), "{}", stop);
+        assert_eq!(run(&mut dbg, "bt"), "#0  x3005 in EMIT (x3005)\n#1  x3003 in PRINT (x3002)\n#2  x3000\n");
+        run(&mut dbg, "rs");
+        assert!(run(&mut dbg, "bt").starts_with("#0  x3003 in PRINT"));
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut dbg = debugger(
+            ".ORIG x3000\nLOOP ADD R0, R0, #1\nST R0, COUNT\nADD R1, R0, #-3\nBRn LOOP\nHALT\nCOUNT .FILL #0\n.END",
+        );
+
+        assert!(run(&mut dbg, "c").contains("Program halted"));
+        let found = run(&mut dbg, "last-write COUNT");
+        assert!(found.contains("x3005 (COUNT) changed x0002 -> x0003 at step 9"), "{}", found);
+        assert!(found.contains("x3001"));
+
+        run(&mut dbg, "break x3001");
+        assert!(run(&mut dbg, "rc").contains("Breakpoint at x3001"));
+        assert_eq!(dbg.vm().peek(0x3005), 2);
+        assert!(run(&mut dbg, "rs 2").contains("=> x3003"));
+        assert!(run(&mut dbg, "rs 100").contains("No more history"));
+        assert_eq!(dbg.vm_mut().read_reg(0), 0);
+    }
+}
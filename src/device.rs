@@ -0,0 +1,305 @@
+use std::any::Any;
+use std::ops::RangeInclusive;
+use thiserror::Error;
+use crate::console::Console;
+use crate::interrupt::{Interrupt, InterruptController, KEYBOARD_PRIORITY, KEYBOARD_VECTOR};
+use crate::vm::{MR_DDR, MR_DSR, MR_KBDR, MR_KBSR};
+
+const KBSR_READY: u16 = 1 << 15;
+const KBSR_INTERRUPT_ENABLE: u16 = 1 << 14;
+const DSR_READY: u16 = 1 << 15;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DeviceError {
+    #[error("Device {name} at x{start:04X}-x{end:04X} overlaps {existing}")]
+    Overlap { name: String, existing: String, start: u16, end: u16 },
+    #[error("Too many devices, at most 255 can be attached")]
+    TooMany,
+}
+
+/// What a device may touch besides its own registers.
+pub struct DeviceContext<'a> {
+    pub console: &'a mut dyn Console,
+    pub interrupts: &'a mut InterruptController,
+}
+
+/// A memory-mapped peripheral. Accesses to the addresses a device is
+/// attached to are routed to it instead of RAM.
+pub trait Device: Any {
+    fn name(&self) -> &str;
+
+    /// Value of a register without side effects, used by debuggers.
+    fn peek(&self, addr: u16) -> u16;
+
+    /// Value of a register as seen by a load instruction.
+    fn read(&mut self, addr: u16, _ctx: &mut DeviceContext) -> u16 {
+        self.peek(addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u16, ctx: &mut DeviceContext);
+
+    /// Called once before every instruction, e.g. to raise interrupts.
+    fn tick(&mut self, _ctx: &mut DeviceContext) {}
+
+    /// Internal state to keep in a snapshot.
+    fn save_state(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// Puts back what `save_state` returned.
+    fn restore_state(&mut self, _state: &[u16]) {}
+}
+
+/// Devices attached to the address space. Lookups go through a 64K table
+/// of device slots so that plain RAM accesses stay cheap.
+pub struct DeviceBus {
+    devices: Vec<(RangeInclusive<u16>, Box<dyn Device>)>,
+    slots: Box<[u8]>, /* 0 for RAM, otherwise device index + 1 */
+}
+
+impl Default for DeviceBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceBus {
+    pub fn new() -> Self {
+        DeviceBus { devices: Vec::new(), slots: vec![0; 1 << 16].into_boxed_slice() }
+    }
+
+    /// Attaches `device` to every address in `range`. Ranges may not
+    /// overlap a device that is already attached.
+    pub fn attach(&mut self, range: RangeInclusive<u16>, device: impl Device) -> Result<(), DeviceError> {
+        if let Some(slot) = range.clone().map(|addr| self.slots[addr as usize]).find(|&slot| slot != 0) {
+            return Err(DeviceError::Overlap {
+                name: device.name().to_string(),
+                existing: self.devices[slot as usize - 1].1.name().to_string(),
+                start: *range.start(),
+                end: *range.end(),
+            });
+        }
+        let slot = u8::try_from(self.devices.len() + 1).map_err(|_| DeviceError::TooMany)?;
+        for addr in range.clone() {
+            self.slots[addr as usize] = slot;
+        }
+        self.devices.push((range, Box::new(device)));
+        Ok(())
+    }
+
+    /// The device attached at `addr`, if any.
+    pub fn at(&self, addr: u16) -> Option<&dyn Device> {
+        match self.slots[addr as usize] {
+            0 => None,
+            slot => Some(self.devices[slot as usize - 1].1.as_ref()),
+        }
+    }
+
+    pub fn at_mut(&mut self, addr: u16) -> Option<&mut dyn Device> {
+        match self.slots[addr as usize] {
+            0 => None,
+            slot => Some(self.devices[slot as usize - 1].1.as_mut()),
+        }
+    }
+
+    /// The first attached device of type `T`.
+    pub fn get<T: Device>(&self) -> Option<&T> {
+        self.devices.iter().find_map(|(_, device)| (device.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn get_mut<T: Device>(&mut self) -> Option<&mut T> {
+        self.devices.iter_mut().find_map(|(_, device)| (device.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Attached devices with their address ranges, in attach order.
+    pub fn iter(&self) -> impl Iterator<Item = (&RangeInclusive<u16>, &dyn Device)> {
+        self.devices.iter().map(|(range, device)| (range, device.as_ref()))
+    }
+
+    /// Name and saved state of every device, in attach order.
+    pub fn save_states(&self) -> Vec<(String, Vec<u16>)> {
+        self.devices.iter().map(|(_, device)| (device.name().to_string(), device.save_state())).collect()
+    }
+
+    /// Restores saved states onto the attached devices with the same name.
+    /// Returns the name of the first state that has no device.
+    pub fn restore_states(&mut self, states: &[(String, Vec<u16>)]) -> Result<(), String> {
+        for (name, state) in states {
+            let (_, device) = self.devices.iter_mut().find(|(_, d)| d.name() == name).ok_or_else(|| name.clone())?;
+            device.restore_state(state);
+        }
+        Ok(())
+    }
+
+    pub fn tick(&mut self, ctx: &mut DeviceContext) {
+        for (_, device) in &mut self.devices {
+            device.tick(ctx);
+        }
+    }
+}
+
+/// Keyboard status (KBSR) and data (KBDR) registers.
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    status: u16,
+    data: u16,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Latches a key into KBDR if one is available, without blocking.
+    // A key that has not been read from KBDR yet is kept.
+    fn poll(&mut self, console: &mut dyn Console) {
+        if self.status & KBSR_READY != 0 {
+            return;
+        }
+        // A failed read (e.g. closed stdin) simply reports no key.
+        if let Ok(Some(key)) = console.poll_byte() {
+            self.status |= KBSR_READY;
+            self.data = key as u16;
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            MR_KBSR => self.status,
+            MR_KBDR => self.data,
+            _ => 0,
+        }
+    }
+
+    fn read(&mut self, addr: u16, ctx: &mut DeviceContext) -> u16 {
+        match addr {
+            MR_KBSR => self.poll(ctx.console),
+            // Reading the data register consumes the latched key.
+            MR_KBDR => self.status &= !KBSR_READY,
+            _ => {}
+        }
+        self.peek(addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u16, _ctx: &mut DeviceContext) {
+        if addr == MR_KBSR {
+            // Only the interrupt-enable bit is writable, ready is set by the device.
+            self.status = (self.status & KBSR_READY) | (value & KBSR_INTERRUPT_ENABLE);
+        }
+    }
+
+    // The keyboard interrupt is level triggered: it is requested for as
+    // long as KBSR has both the ready and interrupt-enable bits set.
+    fn tick(&mut self, ctx: &mut DeviceContext) {
+        if self.status & KBSR_INTERRUPT_ENABLE != 0 {
+            self.poll(ctx.console);
+        }
+        if self.status & (KBSR_READY | KBSR_INTERRUPT_ENABLE) == KBSR_READY | KBSR_INTERRUPT_ENABLE {
+            ctx.interrupts.raise(Interrupt { vector: KEYBOARD_VECTOR, priority: KEYBOARD_PRIORITY });
+        } else {
+            ctx.interrupts.clear(KEYBOARD_VECTOR);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.status, self.data]
+    }
+
+    fn restore_state(&mut self, state: &[u16]) {
+        if let [status, data] = *state {
+            self.status = status;
+            self.data = data;
+        }
+    }
+}
+
+/// Display status (DSR) and data (DDR) registers. Console output is
+/// synchronous, so the display is always ready.
+#[derive(Debug, Default)]
+pub struct Display {
+    data: u16,
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for Display {
+    fn name(&self) -> &str {
+        "display"
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            MR_DSR => DSR_READY,
+            MR_DDR => self.data,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u16, ctx: &mut DeviceContext) {
+        if addr == MR_DDR {
+            self.data = value;
+            // A failed write has nowhere to be reported from a store.
+            let _ = ctx.console.write_bytes(&[value as u8]).and_then(|_| ctx.console.flush());
+        }
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.data]
+    }
+
+    fn restore_state(&mut self, state: &[u16]) {
+        if let [data] = *state {
+            self.data = data;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::VM;
+
+    // A lab-style peripheral: reads return how many times it was written.
+    struct Counter(u16);
+
+    impl Device for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn peek(&self, _addr: u16) -> u16 {
+            self.0
+        }
+
+        fn write(&mut self, _addr: u16, _value: u16, _ctx: &mut DeviceContext) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn test_custom_device_is_routed_and_downcast() {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.attach_device(0xFE10..=0xFE11, Counter(0)).unwrap();
+        vm.mem_write(0xFE10, 7);
+        vm.mem_write(0xFE11, 7);
+
+        assert_eq!(vm.mem_read(0xFE10), 2);
+        assert_eq!(vm.peek(0xFE11), 2);
+        assert_eq!(vm.devices().get::<Counter>().map(|c| c.0), Some(2));
+        assert!(matches!(
+            vm.attach_device(0xFE00..=0xFE00, Counter(0)),
+            Err(DeviceError::Overlap { .. })
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+use crate::symbols::SymbolTable;
+use crate::vm::{Opcodes, TrapCodes, VM};
+
+/// One decoded memory word.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    pub word: u16,
+    pub label: Option<String>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = self.label.as_deref().unwrap_or("");
+        write!(f, "x{:04X}  x{:04X}  {:<12} {}", self.addr, self.word, label, self.text)
+    }
+}
+
+fn sign_extend(value: u16, bit_count: u32) -> i16 {
+    let shift = 16 - bit_count;
+    ((value << shift) as i16) >> shift
+}
+
+fn target(addr: u16, offset: i16, symbols: Option<&SymbolTable>) -> String {
+    let target = addr.wrapping_add(1).wrapping_add(offset as u16);
+    match symbols.and_then(|s| s.name_at(target)) {
+        Some(name) => name.to_string(),
+        None => format!("x{:04X}", target),
+    }
+}
+
+/// Decodes `word`, stored at `addr`, into LC-3 assembly. PC-relative
+/// operands are resolved to absolute addresses, or to label names when
+/// a symbol table is given.
+pub fn disassemble(addr: u16, word: u16, symbols: Option<&SymbolTable>) -> String {
+    let dr = (word >> 9) & 0x7;
+    let sr1 = (word >> 6) & 0x7;
+    let imm5 = sign_extend(word & 0x1F, 5);
+    let offset6 = sign_extend(word & 0x3F, 6);
+    let offset9 = sign_extend(word & 0x1FF, 9);
+    let offset11 = sign_extend(word & 0x7FF, 11);
+
+    let Ok(operation) = Opcodes::try_from(word >> 12) else {
+        return format!(".FILL x{:04X}", word);
+    };
+    match operation {
+        Opcodes::ADD | Opcodes::AND => {
+            let name = if operation == Opcodes::ADD { "ADD" } else { "AND" };
+            if word & 0x20 != 0 {
+                format!("{} R{}, R{}, #{}", name, dr, sr1, imm5)
+            } else {
+                format!("{} R{}, R{}, R{}", name, dr, sr1, word & 0x7)
+            }
+        }
+        Opcodes::BR => {
+            if dr == 0 {
+                return "NOP".to_string();
+            }
+            let mut name = String::from("BR");
+            for (bit, flag) in [(0x4, 'n'), (0x2, 'z'), (0x1, 'p')] {
+                if dr & bit != 0 {
+                    name.push(flag);
+                }
+            }
+            format!("{} {}", name, target(addr, offset9, symbols))
+        }
+        Opcodes::JMP if sr1 == 7 => "RET".to_string(),
+        Opcodes::JMP => format!("JMP R{}", sr1),
+        Opcodes::JSR if word & 0x800 != 0 => format!("JSR {}", target(addr, offset11, symbols)),
+        Opcodes::JSR => format!("JSRR R{}", sr1),
+        Opcodes::LD => format!("LD R{}, {}", dr, target(addr, offset9, symbols)),
+        Opcodes::LDI => format!("LDI R{}, {}", dr, target(addr, offset9, symbols)),
+        Opcodes::LEA => format!("LEA R{}, {}", dr, target(addr, offset9, symbols)),
+        Opcodes::ST => format!("ST R{}, {}", dr, target(addr, offset9, symbols)),
+        Opcodes::STI => format!("STI R{}, {}", dr, target(addr, offset9, symbols)),
+        Opcodes::LDR => format!("LDR R{}, R{}, #{}", dr, sr1, offset6),
+        Opcodes::STR => format!("STR R{}, R{}, #{}", dr, sr1, offset6),
+        Opcodes::NOT => format!("NOT R{}, R{}", dr, sr1),
+        Opcodes::RTI => "RTI".to_string(),
+        Opcodes::RES => format!("RES x{:04X}", word),
+        Opcodes::TRAP => match TrapCodes::try_from(word & 0xFF) {
+            Ok(trap) => trap.name().to_string(),
+            Err(vector) => format!("TRAP x{:02X}", vector),
+        },
+    }
+}
+
+/// Disassembles a block of words loaded at `origin`.
+pub fn disassemble_words(origin: u16, words: &[u16], symbols: Option<&SymbolTable>) -> Vec<Line> {
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, &word)| {
+            let addr = origin.wrapping_add(i as u16);
+            Line {
+                addr,
+                word,
+                label: symbols.and_then(|s| s.name_at(addr)).map(str::to_string),
+                text: disassemble(addr, word, symbols),
+            }
+        })
+        .collect()
+}
+
+/// Disassembles `count` words of live VM memory starting at `start`.
+pub fn disassemble_memory(vm: &VM, start: u16, count: u16, symbols: Option<&SymbolTable>) -> Vec<Line> {
+    let words: Vec<u16> = (0..count).map(|i| vm.peek(start.wrapping_add(i))).collect();
+    disassemble_words(start, &words, symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_resolves_targets_and_traps() {
+        assert_eq!(disassemble(0x3000, 0x127F, None), "ADD R1, R1, #-1");
+        assert_eq!(disassemble(0x3001, 0x03FE, None), "BRp x3000");
+        assert_eq!(disassemble(0x3002, 0xF022, None), "PUTS");
+        assert_eq!(disassemble(0x3003, 0xF030, None), "TRAP x30");
+        assert_eq!(disassemble(0x3004, 0xC1C0, None), "RET");
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3000);
+        assert_eq!(disassemble(0x3001, 0x03FE, Some(&symbols)), "BRp LOOP");
+    }
+
+    #[test]
+    fn test_disassembly_of_assembled_source() {
+        let source = ".ORIG x3000\nLEA R0, MSG\nJSR SUB\nSUB LDR R1, R0, #-2\nRET\nMSG .FILL x41\n.END";
+        let assembly = crate::assembler::assemble(source).unwrap();
+        let lines = disassemble_words(assembly.origin, &assembly.words, Some(&assembly.symbols));
+
+        let texts: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["LEA R0, MSG", "JSR SUB", "LDR R1, R0, #-2", "RET", "NOP"]);
+        assert_eq!(lines[2].label.as_deref(), Some("SUB"));
+    }
+}
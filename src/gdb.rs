@@ -0,0 +1,481 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::ops::Range;
+use std::os::unix::net::UnixStream;
+use crate::vm::{Register, StepOutcome, VM};
+
+// GDB addresses memory in bytes while LC-3 memory is word addressed. Word
+// `w` is exposed as bytes `2w` (low half) and `2w + 1` (high half), and
+// the PC is reported as a 32-bit byte address so that `$pc` can be used
+// directly in memory commands and breakpoints.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="int16"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cond" bitsize="16" type="int16"/>
+  </feature>
+</target>
+"#;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Packet size advertised in qSupported.
+const PACKET_SIZE: u32 = 0x1000;
+// Bytes of memory one `m` reply can carry, hex encoded.
+const MAX_MEMORY_READ: u32 = PACKET_SIZE / 2;
+// Byte addresses past the last word.
+const BYTE_SPACE: u32 = 2 << 16;
+
+// How many instructions run between checks for a ^C from the client.
+const INTERRUPT_POLL_INTERVAL: u32 = 1024;
+
+/// A client connection that can be polled for the interrupt byte (0x03)
+/// while the target is running.
+pub trait Connection: Read + Write {
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+macro_rules! impl_connection {
+    ($stream:ty) => {
+        impl Connection for $stream {
+            fn poll_interrupt(&mut self) -> io::Result<bool> {
+                self.set_nonblocking(true)?;
+                let mut byte = [0u8; 1];
+                let result = self.read(&mut byte);
+                self.set_nonblocking(false)?;
+                match result {
+                    Ok(1) => Ok(byte[0] == 0x03),
+                    Ok(_) => Ok(false),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    };
+}
+
+impl_connection!(TcpStream);
+impl_connection!(UnixStream);
+
+enum Packet {
+    Command(String),
+    Interrupt,
+    Closed,
+}
+
+/// GDB remote serial protocol server driving a VM.
+pub struct GdbStub<'a> {
+    vm: &'a mut VM,
+    breakpoints: BTreeSet<u16>,
+    no_ack: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(vm: &'a mut VM) -> Self {
+        vm.turn_on();
+        GdbStub { vm, breakpoints: BTreeSet::new(), no_ack: false }
+    }
+
+    /// Serves one client until it detaches, kills the target or disconnects.
+    pub fn serve(&mut self, conn: &mut impl Connection) -> io::Result<()> {
+        loop {
+            let command = match self.read_packet(conn)? {
+                Packet::Command(command) => command,
+                Packet::Interrupt => {
+                    self.send(conn, &format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                Packet::Closed => return Ok(()),
+            };
+            match command.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.send(conn, "OK")?;
+                    return Ok(());
+                }
+                _ => {
+                    let reply = self.handle(&command, conn)?;
+                    self.send(conn, &reply)?;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, command: &str, conn: &mut impl Connection) -> io::Result<String> {
+        let reply = if command == "?" {
+            format!("S{:02x}", SIGTRAP)
+        } else if command.starts_with("qSupported") {
+            format!("PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+", PACKET_SIZE)
+        } else if command == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if let Some(args) = command.strip_prefix("qXfer:features:read:target.xml:") {
+            read_xfer(TARGET_XML, args).unwrap_or_else(|| "E01".to_string())
+        } else if command == "qAttached" {
+            "1".to_string()
+        } else if command == "qC" {
+            "QC1".to_string()
+        } else if command == "qfThreadInfo" {
+            "m1".to_string()
+        } else if command == "qsThreadInfo" {
+            "l".to_string()
+        } else if command.starts_with('H') {
+            "OK".to_string()
+        } else if command == "vCont?" {
+            "vCont;c;s".to_string()
+        } else if command == "g" {
+            self.read_registers()
+        } else if let Some(data) = command.strip_prefix('G') {
+            self.write_registers(data).unwrap_or_else(|| "E01".to_string())
+        } else if let Some(reg) = command.strip_prefix('p') {
+            usize::from_str_radix(reg, 16)
+                .ok()
+                .and_then(|reg| self.read_register(reg))
+                .unwrap_or_else(|| "E01".to_string())
+        } else if let Some(args) = command.strip_prefix('P') {
+            self.write_register(args).unwrap_or_else(|| "E01".to_string())
+        } else if let Some(args) = command.strip_prefix('m') {
+            self.read_memory(args).unwrap_or_else(|| "E01".to_string())
+        } else if let Some(args) = command.strip_prefix('M') {
+            self.write_memory(args).unwrap_or_else(|| "E01".to_string())
+        } else if let Some(args) = command.strip_prefix("Z0,") {
+            match parse_breakpoint(args) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            }
+        } else if let Some(args) = command.strip_prefix("z0,") {
+            match parse_breakpoint(args) {
+                Some(addr) => {
+                    self.breakpoints.remove(&addr);
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            }
+        } else if command == "s" || command.starts_with("vCont;s") {
+            self.step(conn)?
+        } else if command == "c" || command.starts_with("vCont;c") {
+            self.resume(conn)?
+        } else {
+            String::new()
+        };
+        Ok(reply)
+    }
+
+    // Executes one instruction, returning the stop reply if the target
+    // can no longer run. Errors are shown on the client's console first.
+    fn execute(&mut self, conn: &mut impl Connection) -> io::Result<Option<String>> {
+        match self.vm.step() {
+            Ok(StepOutcome::Halted) => Ok(Some("W00".to_string())),
+            Ok(_) => Ok(None),
+            Err(e) => {
+                self.send(conn, &format!("O{}", encode_hex(format!("{}\n", e).as_bytes())))?;
+                Ok(Some(format!("S{:02x}", SIGILL)))
+            }
+        }
+    }
+
+    fn step(&mut self, conn: &mut impl Connection) -> io::Result<String> {
+        Ok(self.execute(conn)?.unwrap_or_else(|| format!("S{:02x}", SIGTRAP)))
+    }
+
+    fn resume(&mut self, conn: &mut impl Connection) -> io::Result<String> {
+        let mut since_poll = 0;
+        loop {
+            if let Some(reply) = self.execute(conn)? {
+                return Ok(reply);
+            }
+            if self.breakpoints.contains(&self.vm.get_pc()) {
+                return Ok(format!("T{:02x}swbreak:;", SIGTRAP));
+            }
+            since_poll += 1;
+            if since_poll == INTERRUPT_POLL_INTERVAL {
+                since_poll = 0;
+                if conn.poll_interrupt()? {
+                    return Ok(format!("S{:02x}", SIGINT));
+                }
+            }
+        }
+    }
+
+    fn read_register(&mut self, reg: usize) -> Option<String> {
+        if reg == Register::PC as usize {
+            let pc = self.vm.get_pc() as u32 * 2;
+            Some(encode_hex(&pc.to_le_bytes()))
+        } else if reg < Register::COUNT as usize {
+            Some(encode_hex(&self.vm.read_reg(reg).to_le_bytes()))
+        } else {
+            None
+        }
+    }
+
+    fn read_registers(&mut self) -> String {
+        (0..Register::COUNT as usize).filter_map(|reg| self.read_register(reg)).collect()
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (reg, value) = args.split_once('=')?;
+        let reg = usize::from_str_radix(reg, 16).ok()?;
+        let bytes = decode_hex(value)?;
+        self.set_register(reg, &bytes)?;
+        Some("OK".to_string())
+    }
+
+    fn write_registers(&mut self, data: &str) -> Option<String> {
+        let bytes = decode_hex(data)?;
+        let mut offset = 0;
+        for reg in 0..Register::COUNT as usize {
+            let size = if reg == Register::PC as usize { 4 } else { 2 };
+            self.set_register(reg, bytes.get(offset..offset + size)?)?;
+            offset += size;
+        }
+        Some("OK".to_string())
+    }
+
+    fn set_register(&mut self, reg: usize, bytes: &[u8]) -> Option<()> {
+        if reg == Register::PC as usize {
+            let addr = u32::from_le_bytes(bytes.try_into().ok()?);
+            self.vm.set_pc((addr / 2) as u16);
+        } else if reg < Register::COUNT as usize {
+            self.vm.set_reg(reg, u16::from_le_bytes(bytes.try_into().ok()?));
+        } else {
+            return None;
+        }
+        Some(())
+    }
+
+    fn read_memory(&mut self, args: &str) -> Option<String> {
+        let range = parse_byte_range(args).filter(|range| range.len() as u32 <= MAX_MEMORY_READ)?;
+        let bytes: Vec<u8> = range
+            .map(|byte| self.vm.peek((byte / 2) as u16).to_le_bytes()[(byte % 2) as usize])
+            .collect();
+        Some(encode_hex(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let range = parse_byte_range(range)?;
+        let bytes = decode_hex(data)?;
+        // Like the debugger's `set`, a poke: devices can't be written.
+        if bytes.len() != range.len() || range.clone().any(|byte| self.vm.devices().at((byte / 2) as u16).is_some()) {
+            return None;
+        }
+        for (byte_addr, byte) in range.zip(bytes) {
+            let word_addr = (byte_addr / 2) as u16;
+            let mut word = self.vm.peek(word_addr).to_le_bytes();
+            word[(byte_addr % 2) as usize] = byte;
+            self.vm.poke(word_addr, u16::from_le_bytes(word));
+        }
+        Some("OK".to_string())
+    }
+
+    // Packets with a bad checksum are nacked and skipped.
+    fn read_packet(&mut self, conn: &mut impl Connection) -> io::Result<Packet> {
+        loop {
+            let packet = self.read_one_packet(conn)?;
+            if let Some(packet) = packet {
+                return Ok(packet);
+            }
+        }
+    }
+
+    fn read_one_packet(&mut self, conn: &mut impl Connection) -> io::Result<Option<Packet>> {
+        let mut byte = [0u8; 1];
+        loop {
+            if conn.read(&mut byte)? == 0 {
+                return Ok(Some(Packet::Closed));
+            }
+            match byte[0] {
+                b'$' => break,
+                0x03 => return Ok(Some(Packet::Interrupt)),
+                _ => {} // acks and line noise
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if conn.read(&mut byte)? == 0 {
+                return Ok(Some(Packet::Closed));
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        conn.read_exact(&mut checksum)?;
+
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+        if !self.no_ack {
+            let valid = expected == Some(data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+            conn.write_all(if valid { b"+" } else { b"-" })?;
+            if !valid {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Packet::Command(String::from_utf8_lossy(&data).into_owned())))
+    }
+
+    fn send(&mut self, conn: &mut impl Connection, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        loop {
+            conn.write_all(packet.as_bytes())?;
+            conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            let mut ack = [0u8; 1];
+            if conn.read(&mut ack)? == 0 || ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn read_xfer(document: &str, args: &str) -> Option<String> {
+    let (offset, length) = parse_range(args)?;
+    let bytes = document.as_bytes();
+    let start = (offset as usize).min(bytes.len());
+    let end = (start + length as usize).min(bytes.len());
+    let prefix = if end == bytes.len() { 'l' } else { 'm' };
+    Some(format!("{}{}", prefix, String::from_utf8_lossy(&bytes[start..end])))
+}
+
+fn parse_range(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u32::from_str_radix(addr, 16).ok()?, u32::from_str_radix(len, 16).ok()?))
+}
+
+// `addr,len` in bytes, refusing ranges that run past the last word.
+fn parse_byte_range(args: &str) -> Option<Range<u32>> {
+    let (addr, len) = parse_range(args)?;
+    let end = addr.checked_add(len).filter(|&end| end <= BYTE_SPACE)?;
+    Some(addr..end)
+}
+
+// `Z0,addr,kind`: the byte address of a word is twice its LC-3 address.
+fn parse_breakpoint(args: &str) -> Option<u16> {
+    let (addr, _kind) = args.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    u16::try_from(addr / 2).ok()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::console::NullConsole;
+
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(stream, "${}#{:02x}", data, checksum).unwrap();
+
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_scripted_client_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            // A corrupted packet is nacked and the stub waits for a resend.
+            write!(stream, "$?#00").unwrap();
+            let mut nack = [0u8; 1];
+            stream.read_exact(&mut nack).unwrap();
+            assert_eq!(nack[0], b'-');
+            let replies = vec![
+                request(&mut stream, "?"),
+                // Word x3000 is ADD R0, R0, #5 (x1025): bytes 25 10 at x6000.
+                request(&mut stream, "m6000,2"),
+                // Break on x3002 (byte address x6004), then continue.
+                request(&mut stream, "Z0,6004,2"),
+                request(&mut stream, "c"),
+                request(&mut stream, "p0"),
+                request(&mut stream, "p8"),
+                request(&mut stream, "M6000,2:ff10"),
+                request(&mut stream, "s"),
+                request(&mut stream, "c"),
+                request(&mut stream, "mffffffff,2"),
+                request(&mut stream, "m1fffe,4"),
+                request(&mut stream, "m0,100000"),
+                request(&mut stream, "M1fffe,4:00000000"),
+                request(&mut stream, "M1fc00,2:0000"), // KBSR, a device register
+            ];
+            write!(stream, "$k#6b").unwrap();
+            stream.read_exact(&mut [0u8; 1]).unwrap();
+            replies
+        });
+
+        let mut vm = VM::with_console(NullConsole);
+        vm.mem_write(0x3000, 0x1025); // ADD R0, R0, #5
+        vm.mem_write(0x3001, 0x1025); // ADD R0, R0, #5
+        vm.mem_write(0x3002, 0x1021); // ADD R0, R0, #1
+        vm.mem_write(0x3003, 0xF025); // HALT
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_nodelay(true).unwrap();
+        GdbStub::new(&mut vm).serve(&mut conn).unwrap();
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0], "S05");
+        assert_eq!(replies[1], "2510");
+        assert_eq!(replies[2], "OK");
+        assert_eq!(replies[3], "T05swbreak:;");
+        assert_eq!(replies[4], "0a00");
+        assert_eq!(replies[5], "04600000");
+        assert_eq!(replies[6], "OK");
+        assert_eq!(replies[7], "S05");
+        assert_eq!(replies[8], "W00");
+        assert!(replies[9..].iter().all(|reply| reply == "E01"), "{:?}", &replies[9..]);
+        assert_eq!(vm.peek(0x3000), 0x10FF);
+        assert_eq!(vm.read_reg(0), 11);
+    }
+}
@@ -0,0 +1,97 @@
+/// Base of the interrupt vector table; vector `v` is read from `0x0100 + v`.
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+pub const KEYBOARD_PRIORITY: u8 = 4;
+pub const TIMER_VECTOR: u8 = 0x81;
+pub const TIMER_PRIORITY: u8 = 5;
+
+/// Exceptions raised by the processor itself, numbered by their vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    PrivilegeViolation = 0x00, /* RTI in user mode */
+    IllegalOpcode = 0x01,      /* reserved opcode */
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+}
+
+/// How the VM handles an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExceptionPolicy {
+    /// Stop and return the `VMError` to the host.
+    #[default]
+    ReturnError,
+    /// Enter the handler from the interrupt vector table, like hardware.
+    Vector,
+}
+
+/// A device interrupt request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+    pub vector: u8,
+    pub priority: u8, /* 0-7, must exceed the PSR priority to be taken */
+}
+
+/// Pending interrupt requests, checked by the VM between instructions.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptController {
+    pending: Vec<Interrupt>,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests an interrupt. Raising a vector that is already pending
+    /// only updates its priority.
+    pub fn raise(&mut self, interrupt: Interrupt) {
+        let priority = interrupt.priority.min(7);
+        match self.pending.iter_mut().find(|p| p.vector == interrupt.vector) {
+            Some(pending) => pending.priority = priority,
+            None => self.pending.push(Interrupt { vector: interrupt.vector, priority }),
+        }
+    }
+
+    /// Withdraws a request that has not been serviced yet.
+    pub fn clear(&mut self, vector: u8) {
+        self.pending.retain(|p| p.vector != vector);
+    }
+
+    pub fn pending(&self) -> &[Interrupt] {
+        &self.pending
+    }
+
+    /// Removes and returns the highest-priority request above `level`.
+    /// Ties go to the request raised first.
+    pub fn take_above(&mut self, level: u8) -> Option<Interrupt> {
+        let (index, _) = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.priority > level)
+            .min_by_key(|(i, p)| (std::cmp::Reverse(p.priority), *i))?;
+        Some(self.pending.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_above_respects_priority() {
+        let mut controller = InterruptController::new();
+        controller.raise(Interrupt { vector: 0x81, priority: 2 });
+        controller.raise(Interrupt { vector: 0x80, priority: 4 });
+
+        assert_eq!(controller.take_above(4), None);
+        assert_eq!(controller.take_above(1), Some(Interrupt { vector: 0x80, priority: 4 }));
+        assert_eq!(controller.take_above(1), Some(Interrupt { vector: 0x81, priority: 2 }));
+        assert!(controller.pending().is_empty());
+    }
+}
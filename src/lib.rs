@@ -1,9 +1,31 @@
+pub mod assembler;
+pub mod callstack;
+pub mod console;
+pub mod debugger;
+pub mod device;
+pub mod disassembler;
+pub mod gdb;
+pub mod interrupt;
+pub mod limits;
+pub mod loader;
+pub mod number;
+pub mod os;
+pub mod profile;
+pub mod snapshot;
+pub mod symbols;
+pub mod terminal;
+pub mod timer;
+pub mod trace;
+pub mod undo;
+pub mod video;
+pub mod watch;
 pub mod vm;
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use crate::vm::VM;
+    use crate::console::BufferConsole;
+    use crate::interrupt::{Exception, ExceptionPolicy};
+    use crate::vm::{Register, StepOutcome, VMError, VM};
 
     #[test]
     fn test_mem_write_and_read() {
@@ -14,4 +36,212 @@ mod tests {
 
         assert_eq!(value, 0x1234);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_step_executes_one_instruction() {
+        let mut vm = VM::new();
+        vm.mem_write(0x3000, 0x1025); // ADD R0, R0, #5
+        vm.mem_write(0x3001, 0xF025); // HALT
+        vm.turn_on();
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        assert_eq!(vm.read_reg(Register::R0 as usize), 5);
+        assert_eq!(vm.get_pc(), 0x3001);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Halted);
+        assert!(!vm.is_running());
+    }
+
+    #[test]
+    fn test_run_until_halt() {
+        let mut vm = VM::new();
+        vm.mem_write(0x3000, 0x1025); // ADD R0, R0, #5
+        vm.mem_write(0x3001, 0x103F); // ADD R0, R0, #-1
+        vm.mem_write(0x3002, 0x03FE); // BRp #-2
+        vm.mem_write(0x3003, 0xF025); // HALT
+
+        assert_eq!(vm.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(vm.read_reg(Register::R0 as usize), 0);
+    }
+
+    #[test]
+    fn test_reserved_opcode_reports_pc_and_instruction() {
+        let mut vm = VM::new();
+        vm.mem_write(0x3000, 0x1025); // ADD R0, R0, #5
+        vm.mem_write(0x3001, 0xD000); // RES
+
+        match vm.run() {
+            Err(VMError::ReservedOpcode { pc, instruction }) => {
+                assert_eq!(pc, 0x3001);
+                assert_eq!(instruction, 0xD000);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_trap_vector_is_an_error() {
+        let mut vm = VM::new();
+        vm.mem_write(0x3000, 0xF0FF); // TRAP xFF
+        vm.set_reg(7, 0x1234);
+
+        match vm.run() {
+            Err(VMError::UnknownTrap { pc, vector, .. }) => {
+                assert_eq!(pc, 0x3000);
+                assert_eq!(vector, 0xFF);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(vm.read_reg(7), 0x1234);
+    }
+
+    #[test]
+    fn test_traps_use_injected_console() {
+        let console = BufferConsole::new(b"x");
+        let mut vm = VM::with_console(console.clone());
+        vm.mem_write(0x3000, 0xF020); // GETC
+        vm.mem_write(0x3001, 0xF021); // OUT
+        vm.mem_write(0x3002, 0xE002); // LEA R0, #2
+        vm.mem_write(0x3003, 0xF022); // PUTS
+        vm.mem_write(0x3004, 0xF025); // HALT
+        vm.mem_write(0x3005, 'o' as u16);
+        vm.mem_write(0x3006, 'k' as u16);
+
+        assert_eq!(vm.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), b"xok");
+    }
+
+    #[test]
+    fn test_kbsr_does_not_block_without_input() {
+        let console = BufferConsole::new(b"");
+        let mut vm = VM::with_console(console.clone());
+
+        assert_eq!(vm.mem_read(0xFE00) & 0x8000, 0);
+
+        console.push_input(b"q");
+        assert_eq!(vm.mem_read(0xFE00) & 0x8000, 0x8000);
+        // The key stays latched until KBDR is read.
+        assert_eq!(vm.mem_read(0xFE00) & 0x8000, 0x8000);
+        assert_eq!(vm.mem_read(0xFE02), 'q' as u16);
+        assert_eq!(vm.mem_read(0xFE00) & 0x8000, 0);
+    }
+
+    #[test]
+    fn test_rti_returns_to_user_mode_and_swaps_stacks() {
+        let mut vm = VM::new();
+        vm.set_psr(0x0102); // supervisor, priority 1, Z
+        vm.set_saved_usp(0xF000);
+        vm.set_reg(Register::R6 as usize, 0x2FFE);
+        vm.mem_write(0x2FFE, 0x3100); // saved PC
+        vm.mem_write(0x2FFF, 0x8001); // saved PSR: user, P
+        vm.mem_write(0x3000, 0x8000); // RTI
+        vm.turn_on();
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        assert_eq!(vm.get_pc(), 0x3100);
+        assert_eq!(vm.get_psr(), 0x8001);
+        assert!(!vm.is_supervisor());
+        assert_eq!(vm.read_reg(Register::R6 as usize), 0xF000);
+        assert_eq!(vm.saved_ssp(), 0x3000);
+    }
+
+    #[test]
+    fn test_rti_in_user_mode_is_a_privilege_violation() {
+        let mut vm = VM::new();
+        vm.mem_write(0x3000, 0x8000); // RTI
+
+        assert!(matches!(vm.run(), Err(VMError::PrivilegeViolation { pc: 0x3000, .. })));
+    }
+
+    #[test]
+    fn test_keyboard_interrupt_runs_handler_and_returns() {
+        let console = BufferConsole::new(b"");
+        let mut vm = VM::with_console(console.clone());
+        vm.mem_write(0x0180, 0x1000); // keyboard vector x80
+        vm.mem_write(0x1000, 0xA201); // LDI R1, KBDR_PTR
+        vm.mem_write(0x1001, 0x8000); // RTI
+        vm.mem_write(0x1002, 0xFE02); // KBDR_PTR
+        vm.mem_write(0xFE00, 0x4000); // enable keyboard interrupts
+        vm.mem_write(0x3000, 0x0FFF); // BRnzp #-1 (spin)
+        vm.turn_on();
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        console.push_input(b"k");
+        assert_eq!(vm.step().unwrap(), StepOutcome::Interrupted(0x80));
+        assert!(vm.is_supervisor());
+        assert_eq!(vm.priority(), 4);
+        assert_eq!(vm.get_pc(), 0x1000);
+
+        vm.step().unwrap(); // LDI clears the ready bit
+        vm.step().unwrap(); // RTI
+        assert_eq!(vm.read_reg(Register::R1 as usize), 'k' as u16);
+        assert_eq!(vm.get_pc(), 0x3000);
+        assert!(!vm.is_supervisor());
+        assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+    }
+
+    #[test]
+    fn test_raised_interrupt_waits_for_priority() {
+        let mut vm = VM::new();
+        vm.mem_write(0x0181, 0x1000);
+        vm.mem_write(0x3000, 0x0FFF); // BRnzp #-1 (spin)
+        vm.set_psr(0x8302); // user, priority 3
+        vm.raise_interrupt(0x81, 3);
+        vm.turn_on();
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        vm.raise_interrupt(0x81, 5);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Interrupted(0x81));
+        assert_eq!(vm.peek(0x2FFF), 0x8302); // PSR pushed on the supervisor stack
+        assert_eq!(vm.peek(0x2FFE), 0x3000);
+    }
+
+    #[test]
+    fn test_exceptions_vector_when_policy_allows() {
+        let mut vm = VM::new();
+        vm.mem_write(0x0100, 0x1100); // privilege violation handler
+        vm.mem_write(0x0101, 0x1200); // illegal opcode handler
+        vm.mem_write(0x3000, 0xD000); // RES
+        vm.set_exception_policy(Exception::IllegalOpcode, ExceptionPolicy::Vector);
+        vm.turn_on();
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Exception(0x01));
+        assert_eq!(vm.get_pc(), 0x1200);
+        assert!(vm.is_supervisor());
+        assert_eq!(vm.peek(0x2FFE), 0x3001);
+
+        // Privilege violations still go to the host by default.
+        vm.set_psr(0x8002);
+        vm.set_pc(0x3010);
+        vm.mem_write(0x3010, 0x8000); // RTI
+        assert!(matches!(vm.step(), Err(VMError::PrivilegeViolation { .. })));
+        vm.set_exception_policy(Exception::PrivilegeViolation, ExceptionPolicy::Vector);
+        vm.set_pc(0x3010);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Exception(0x00));
+        assert_eq!(vm.get_pc(), 0x1100);
+    }
+
+    #[test]
+    fn test_display_and_mcr_without_traps() {
+        let console = BufferConsole::new(b"");
+        let mut vm = VM::with_console(console.clone());
+        vm.mem_write(0x3000, 0xA204); // LDI R1, DSR
+        vm.mem_write(0x3001, 0x07FE); // BRzp x3000
+        vm.mem_write(0x3002, 0xB403); // STI R2, DDR
+        vm.mem_write(0x3003, 0xB603); // STI R3, MCR
+        vm.mem_write(0x3004, 0x0FFF); // BRnzp x3004, never reached
+        vm.mem_write(0x3005, 0xFE04);
+        vm.mem_write(0x3006, 0xFE06);
+        vm.mem_write(0x3007, 0xFFFE);
+        vm.set_reg(Register::R2 as usize, 'A' as u16);
+        vm.turn_on();
+
+        assert_eq!(vm.mem_read(0xFFFE) & 0x8000, 0x8000);
+        for _ in 0..3 {
+            assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        }
+        assert_eq!(console.output(), b"A");
+        assert_eq!(vm.step().unwrap(), StepOutcome::Halted);
+        assert!(!vm.is_running());
+        assert_eq!(vm.mem_read(0xFFFE) & 0x8000, 0);
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+use crate::vm::StepOutcome;
+
+// Keeps Instant::now() off the hot path.
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+/// Which limit stopped a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunLimit {
+    Instructions(u64), /* the budget that was used up */
+    Timeout(Duration),
+}
+
+impl fmt::Display for RunLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunLimit::Instructions(count) => write!(f, "instruction limit of {} reached", count),
+            RunLimit::Timeout(timeout) => write!(f, "timed out after {:.3}s", timeout.as_secs_f64()),
+        }
+    }
+}
+
+/// Bounds for `VM::run_with_limits`, to stop programs that never halt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunLimits {
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<Duration>, /* wall-clock time */
+}
+
+impl RunLimits {
+    /// Starts counting, and the clock, for one run.
+    pub fn start(&self) -> Budget {
+        Budget { limits: *self, started: Instant::now(), executed: 0, steps: 0 }
+    }
+}
+
+/// What is left of the limits during a run. Step loops outside the VM
+/// can use it too: `charge` every outcome and stop once it returns a
+/// limit.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    limits: RunLimits,
+    started: Instant,
+    executed: u64, /* instructions, not counting interrupt entries */
+    steps: u64,
+}
+
+impl Budget {
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// The instruction limit, if nothing more may run, e.g. a budget of 0
+    /// before the first step.
+    pub fn used_up(&self) -> Option<RunLimit> {
+        match self.limits.max_instructions {
+            Some(max) if self.executed >= max => Some(RunLimit::Instructions(max)),
+            _ => None,
+        }
+    }
+
+    /// Counts a step, returning the limit it used up, if any.
+    pub fn charge(&mut self, outcome: StepOutcome) -> Option<RunLimit> {
+        self.steps += 1;
+        if !matches!(outcome, StepOutcome::Interrupted(_)) {
+            self.executed += 1;
+        }
+        if let Some(limit) = self.used_up() {
+            return Some(limit);
+        }
+        if let Some(timeout) = self.limits.timeout
+            && self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK)
+            && self.started.elapsed() >= timeout
+        {
+            return Some(RunLimit::Timeout(timeout));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::VM;
+
+    fn spinning_vm() -> VM {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(0x3000, 0x1021); // LOOP ADD R0, R0, #1
+        vm.mem_write(0x3001, 0x0FFE); // BRnzp LOOP
+        vm
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let mut vm = spinning_vm();
+        let limits = RunLimits { max_instructions: Some(11), ..RunLimits::default() };
+        assert_eq!(vm.run_with_limits(limits).unwrap(), StepOutcome::LimitReached(RunLimit::Instructions(11)));
+        assert_eq!((vm.read_reg(0), vm.get_pc()), (6, 0x3001));
+        assert_eq!(vm.recent_pcs().len(), 11);
+        assert_eq!(vm.recent_pcs()[9..], [0x3001, 0x3000]);
+
+        // The hook sees every step but the one that used up the budget.
+        let mut steps = 0;
+        spinning_vm().run_with_hook(limits, |_| steps += 1).unwrap();
+        assert_eq!(steps, 10);
+
+        let mut vm = spinning_vm();
+        let limits = RunLimits { max_instructions: Some(0), ..RunLimits::default() };
+        assert_eq!(vm.run_with_limits(limits).unwrap(), StepOutcome::LimitReached(RunLimit::Instructions(0)));
+        assert_eq!((vm.get_pc(), vm.recent_pcs().len()), (0x3000, 0));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut vm = spinning_vm();
+        let timeout = Duration::from_millis(20);
+        let limits = RunLimits { timeout: Some(timeout), ..RunLimits::default() };
+        let started = Instant::now();
+        assert_eq!(vm.run_with_limits(limits).unwrap(), StepOutcome::LimitReached(RunLimit::Timeout(timeout)));
+        assert!(started.elapsed() >= timeout);
+        assert_eq!(vm.recent_pcs().len(), crate::vm::HISTORY_LEN);
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("Failed to read {name}: {source}")]
+    Io { name: String, source: io::Error },
+    #[error("{0} is empty")]
+    Empty(String),
+    #[error("{0} is one byte long, too short for an origin")]
+    MissingOrigin(String),
+    #[error("{name} has an odd length of {len} bytes")]
+    OddLength { name: String, len: usize },
+    #[error("{name} at {origin:#06x} with {len} words runs past the end of memory")]
+    TooLarge { name: String, origin: u16, len: usize },
+    #[error("{0}")]
+    Overlap(Overlap),
+}
+
+/// What to do when an image covers memory written by an earlier one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverlapPolicy {
+    Error,
+    #[default]
+    Warn,
+    LastWins,
+}
+
+impl std::str::FromStr for OverlapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(OverlapPolicy::Error),
+            "warn" => Ok(OverlapPolicy::Warn),
+            "last-wins" => Ok(OverlapPolicy::LastWins),
+            _ => Err(format!("unknown overlap policy `{}` (expected error, warn or last-wins)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    pub earlier: String,
+    pub later: String,
+    pub start: u16,
+    pub end: u16, /* inclusive */
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} overwrites {} at x{:04X}-x{:04X}",
+            self.later, self.earlier, self.start, self.end
+        )
+    }
+}
+
+/// A decoded `.obj` image: big-endian origin followed by big-endian words.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub name: String,
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl Image {
+    pub fn parse(name: &str, bytes: &[u8]) -> Result<Image, LoadError> {
+        match bytes.len() {
+            0 => return Err(LoadError::Empty(name.to_string())),
+            1 => return Err(LoadError::MissingOrigin(name.to_string())),
+            len if len % 2 != 0 => return Err(LoadError::OddLength { name: name.to_string(), len }),
+            _ => {}
+        }
+
+        let origin = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let words: Vec<u16> = bytes[2..]
+            .chunks_exact(2)
+            .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+            .collect();
+        if origin as usize + words.len() > 0x10000 {
+            return Err(LoadError::TooLarge { name: name.to_string(), origin, len: words.len() });
+        }
+        Ok(Image { name: name.to_string(), origin, words })
+    }
+
+    pub fn read(path: &str) -> Result<Image, LoadError> {
+        let bytes = std::fs::read(path).map_err(|source| LoadError::Io { name: path.to_string(), source })?;
+        Image::parse(path, &bytes)
+    }
+
+    /// Last address covered by the image, `None` for an image with no words.
+    /// Fails if the image runs past xFFFF.
+    pub fn end(&self) -> Result<Option<u16>, LoadError> {
+        last_address(&self.name, self.origin, self.words.len())
+    }
+}
+
+fn last_address(name: &str, origin: u16, len: usize) -> Result<Option<u16>, LoadError> {
+    if len == 0 {
+        return Ok(None);
+    }
+    u16::try_from(len - 1)
+        .ok()
+        .and_then(|last| origin.checked_add(last))
+        .map(Some)
+        .ok_or_else(|| LoadError::TooLarge { name: name.to_string(), origin, len })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedImage {
+    pub name: String,
+    pub origin: u16,
+    pub len: usize,
+}
+
+/// Every image loaded so far, in load order, plus the overlaps that were
+/// allowed by the policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadMap {
+    pub images: Vec<LoadedImage>,
+    pub overlaps: Vec<Overlap>,
+}
+
+impl LoadMap {
+    /// Records `image`, checking it against the images already in the map.
+    pub fn add(&mut self, image: &Image, policy: OverlapPolicy) -> Result<(), LoadError> {
+        if let Some(end) = image.end()? {
+            for loaded in &self.images {
+                let Some(loaded_end) = last_address(&loaded.name, loaded.origin, loaded.len)? else {
+                    continue;
+                };
+                let start = image.origin.max(loaded.origin);
+                let overlap_end = end.min(loaded_end);
+                if start > overlap_end {
+                    continue;
+                }
+                let overlap = Overlap {
+                    earlier: loaded.name.clone(),
+                    later: image.name.clone(),
+                    start,
+                    end: overlap_end,
+                };
+                match policy {
+                    OverlapPolicy::Error => return Err(LoadError::Overlap(overlap)),
+                    OverlapPolicy::Warn => self.overlaps.push(overlap),
+                    OverlapPolicy::LastWins => {}
+                }
+            }
+        }
+        self.images.push(LoadedImage { name: image.name.clone(), origin: image.origin, len: image.words.len() });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rejects_malformed_images() {
+        assert!(matches!(Image::parse("a", &[]), Err(LoadError::Empty(_))));
+        assert!(matches!(Image::parse("a", &[0x30]), Err(LoadError::MissingOrigin(_))));
+        assert!(matches!(Image::parse("a", &[0x30, 0x00, 0x12]), Err(LoadError::OddLength { len: 3, .. })));
+        assert!(matches!(Image::parse("a", &[0xFF, 0xFF, 0, 1, 0, 2]), Err(LoadError::TooLarge { .. })));
+
+        let image = Image::parse("a", &[0x30, 0x00, 0x12, 0x34]).unwrap();
+        assert_eq!((image.origin, image.words.clone()), (0x3000, vec![0x1234]));
+
+        // Images built by hand are checked when loaded.
+        let mut vm = crate::vm::VM::with_console(crate::console::NullConsole);
+        let past_end = Image { name: "a".into(), origin: 0xFFFF, words: vec![1, 2] };
+        assert!(matches!(vm.load_image(&past_end), Err(LoadError::TooLarge { origin: 0xFFFF, len: 2, .. })));
+        assert_eq!(vm.peek(0xFFFF), 0);
+    }
+
+    #[test]
+    fn test_load_map_overlap_policies() {
+        let first = Image { name: "os".into(), origin: 0x3000, words: vec![0; 16] };
+        let second = Image { name: "prog".into(), origin: 0x300A, words: vec![0; 16] };
+
+        let mut map = LoadMap::default();
+        map.add(&first, OverlapPolicy::Error).unwrap();
+        match map.add(&second, OverlapPolicy::Error) {
+            Err(LoadError::Overlap(overlap)) => assert_eq!((overlap.start, overlap.end), (0x300A, 0x300F)),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        map.add(&second, OverlapPolicy::Warn).unwrap();
+        assert_eq!(map.overlaps.len(), 1);
+        assert_eq!(map.images.len(), 2);
+
+        let past_end = Image { name: "big".into(), origin: 0xFFF0, words: vec![0; 32] };
+        assert!(matches!(map.add(&past_end, OverlapPolicy::Warn), Err(LoadError::TooLarge { len: 32, .. })));
+        assert_eq!(map.images.len(), 2);
+    }
+}
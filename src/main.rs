@@ -1,215 +1,479 @@
-use std::{env};
-//use std::io;
-use std::io::{Read, Write};
-// use std::process;
-// use std::alloc;
-// use libc::{read, write, close, fork};
-// use std::fs::OpenOptions;
-use libc::{termios, tcgetattr, tcsetattr};
-// use std::time::{Duration, Instant, SystemTime};
-// use libc::timeval;
-// use libc::{pid_t, size_t};
-// use termios::Termios;
-use nix::sys::select::{select, FdSet};
-use nix::sys::time::TimeVal;
-use libc::STDIN_FILENO;
-use std::env::args;
-use crate::vm::VM;
-use crate::vm::Register;
-use std::io::stdin;
-use std::os::fd::AsFd;
-static mut ORIGINAL_TIO: Option<termios> = None;
-
-mod vm;
-
-
-fn disable_input_buffering() {
-    unsafe {
-        let fd = STDIN_FILENO;
-
-        let mut term: termios = std::mem::zeroed();
-
-        if libc::tcgetattr(fd, &mut term) != 0 {
-            panic!("tcgetattr failed");
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use lc3_vm::assembler;
+use lc3_vm::callstack::CallStack;
+use lc3_vm::console::TerminalConsole;
+use lc3_vm::debugger::Debugger;
+use lc3_vm::disassembler;
+use lc3_vm::gdb::GdbStub;
+use lc3_vm::interrupt::{Exception, ExceptionPolicy};
+use lc3_vm::limits::RunLimits;
+use lc3_vm::loader::{Image, OverlapPolicy};
+use lc3_vm::number;
+use lc3_vm::os;
+use lc3_vm::profile::Profiler;
+use lc3_vm::symbols::SymbolTable;
+use lc3_vm::snapshot::Snapshot;
+use lc3_vm::terminal::{self, TerminalSession};
+use lc3_vm::trace::{TraceFilter, TraceFormat, Tracer};
+use lc3_vm::video::{Framebuffer, VIDEO_BASE, VIDEO_END};
+use lc3_vm::vm::{StepOutcome, TrapMode, VMError, VM};
+use lc3_vm::watch::Watchpoint;
+
+const USAGE: &str = "\
+lc3 [--overlap error|warn|last-wins] [--trap-mode native|memory] [--os]
+    [--video] [--video-frames <dir>] [--screenshot <file.png|file.ppm>]
+    [--snapshot-file <file>] [--resume <file>]
+    [--trace <file>] [--trace-format text|binary] [--trace-range <start>-<end>]
+    [--trace-window <first>:<last>] [--trace-opcodes <OP,...>]
+    [--watch '<addr>[-<end>] [read|write|change] [if <condition>]'] ...
+    [--profile <report.txt>] [--profile-data <file.json|file.csv>]
+    [--max-instructions <n>] [--timeout <seconds>] [image-file] ...
+lc3 asm <source.asm> [-o <image.obj>]
+lc3 disasm <image.obj> [--symbols <file.sym>]
+lc3 debug <image.obj> [--symbols <file.sym>] [--video]
+lc3 gdb <image.obj> [--listen <host:port> | --unix <path>]";
+
+// Rows in each section of the `--profile` report.
+const PROFILE_TOP: usize = 20;
+
+fn main() {
+    //Load arguments
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("asm") => assemble_command(&args[2..]),
+        Some("disasm") => disassemble_command(&args[2..]),
+        Some("debug") => debug_command(&args[2..]),
+        Some("gdb") => gdb_command(&args[2..]),
+        Some(_) => run_command(&args[1..]),
+        None => usage(),
+    }
+}
+
+fn usage() -> ! {
+    println!("{}\n", USAGE);
+    std::process::exit(2);
+}
+
+struct RunOptions {
+    images: Vec<String>,
+    overlap: OverlapPolicy,
+    trap_mode: TrapMode,
+    os: bool,
+    video: bool,
+    video_frames: Option<PathBuf>,
+    screenshot: Option<PathBuf>,
+    snapshot_file: String,
+    resume: Option<String>,
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+    watchpoints: Vec<Watchpoint>,
+    profile: Option<String>,
+    profile_data: Option<String>,
+    limits: RunLimits,
+}
+
+fn parse_run_options(args: &[String]) -> RunOptions {
+    let mut options = RunOptions {
+        images: Vec::new(),
+        overlap: OverlapPolicy::default(),
+        trap_mode: TrapMode::default(),
+        os: false,
+        video: false,
+        video_frames: None,
+        screenshot: None,
+        snapshot_file: "lc3.snap".to_string(),
+        resume: None,
+        trace: None,
+        trace_format: TraceFormat::default(),
+        trace_filter: TraceFilter::default(),
+        watchpoints: Vec::new(),
+        profile: None,
+        profile_data: None,
+        limits: RunLimits::default(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--overlap" => options.overlap = parse_or_exit(args.next(), str::parse),
+            "--trap-mode" => options.trap_mode = parse_or_exit(args.next(), str::parse),
+            "--os" => options.os = true,
+            "--video" => options.video = true,
+            "--video-frames" => options.video_frames = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--screenshot" => options.screenshot = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--snapshot-file" => options.snapshot_file = args.next().unwrap_or_else(|| usage()).clone(),
+            "--resume" => options.resume = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            "--trace" => options.trace = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            "--trace-format" => options.trace_format = parse_or_exit(args.next(), str::parse),
+            "--trace-range" => {
+                options.trace_filter.addresses = Some(parse_or_exit(args.next(), TraceFilter::parse_addresses))
+            }
+            "--trace-window" => options.trace_filter.window = Some(parse_or_exit(args.next(), TraceFilter::parse_window)),
+            "--trace-opcodes" => {
+                options.trace_filter.opcodes = Some(parse_or_exit(args.next(), TraceFilter::parse_opcodes))
+            }
+            "--watch" => options.watchpoints.push(parse_or_exit(args.next(), |spec| Watchpoint::parse(spec, number::parse_word))),
+            "--profile" => options.profile = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            "--profile-data" => options.profile_data = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            "--max-instructions" => {
+                let count = parse_or_exit(args.next(), |n| number::parse_number(n).ok_or_else(|| format!("invalid instruction count `{}`", n)));
+                options.limits.max_instructions = Some(count);
+            }
+            "--timeout" => options.limits.timeout = Some(parse_or_exit(args.next(), parse_seconds)),
+            flag if flag.starts_with("--") => usage(),
+            image => options.images.push(image.to_string()),
+        }
+    }
+    if options.images.is_empty() && options.resume.is_none() {
+        usage();
+    }
+    options
+}
+
+// Parses an option's value, exiting with a usage error if it is missing
+// or invalid.
+fn parse_or_exit<T>(value: Option<&String>, parse: impl Fn(&str) -> Result<T, String>) -> T {
+    let value = value.unwrap_or_else(|| usage());
+    parse(value).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    })
+}
+
+fn parse_seconds(text: &str) -> Result<Duration, String> {
+    text.parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or_else(|| format!("invalid timeout `{}`, expected a number of seconds", text))
+}
+
+/// Loads every image in order and runs until the program halts. With
+/// `--os` the bundled OS is loaded first and services TRAPs and exceptions
+/// from memory. Ctrl-\ or SIGUSR1 save a snapshot while running, and
+/// `--resume` continues from one. A `--watch` hit stops the program with
+/// exit status 3, and `--max-instructions` or `--timeout` with status 124
+/// (like `timeout(1)`). Faults are reported with a backtrace of the subroutine
+/// calls, and stack discipline warnings are printed at the end.
+fn run_command(args: &[String]) {
+    let options = parse_run_options(args);
+
+    let mut vm = VM::new();
+    vm.set_trap_mode(options.trap_mode);
+    if options.os {
+        // The OS lives below x3000 and is not part of the overlap checks,
+        // so user programs may replace its vectors or routines.
+        vm.load_image(&os::os_image()).expect("the OS fits below x3000");
+        vm.set_trap_mode(TrapMode::Memory);
+        vm.set_exception_policy(Exception::PrivilegeViolation, ExceptionPolicy::Vector);
+        vm.set_exception_policy(Exception::IllegalOpcode, ExceptionPolicy::Vector);
+    }
+    if options.video || options.video_frames.is_some() || options.screenshot.is_some() {
+        let mut video = Framebuffer::new();
+        video.set_terminal(options.video);
+        video.set_frame_dir(options.video_frames.clone());
+        attach_video(&mut vm, video);
+    }
+    match vm.load_images(&options.images, options.overlap) {
+        Ok(map) => {
+            for overlap in &map.overlaps {
+                eprintln!("Warning: {}", overlap);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    }
+    if let Some(path) = &options.resume {
+        resume(&mut vm, path, !options.images.is_empty());
+    }
+    if let Some(path) = &options.trace {
+        match Tracer::to_file(path, options.trace_format, options.trace_filter.clone()) {
+            Ok(tracer) => vm.set_tracer(Some(tracer)),
+            Err(e) => {
+                eprintln!("Failed to create {}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+    }
+    for watchpoint in &options.watchpoints {
+        vm.watchpoints_mut().add(watchpoint.clone());
+    }
+    if options.profile.is_some() || options.profile_data.is_some() {
+        vm.set_profiler(Some(Profiler::new()));
+    }
+    vm.set_call_stack(Some(CallStack::new()));
 
-        ORIGINAL_TIO = Some(term);
+    let result = {
+        let _terminal = match TerminalSession::new() {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                eprintln!("Failed to configure terminal: {}", e);
+                std::process::exit(1);
+            }
+        };
+        terminal::watch_snapshot_signals();
+        run_until_halt(&mut vm, &options.snapshot_file, options.limits)
+    };
 
-        // disable ICANON and ECHO
-        term.c_lflag &= !(libc::ICANON | libc::ECHO);
+    if let Some(mut tracer) = vm.set_tracer(None)
+        && let Err(e) = tracer.flush()
+    {
+        eprintln!("Failed to write trace: {}", e);
+    }
+    let symbols = image_symbols(&options.images);
+    if let Some(profiler) = vm.set_profiler(None) {
+        write_profile(&profiler, &symbols, &options);
+    }
+    for warning in vm.call_stack_mut().map(CallStack::take_warnings).unwrap_or_default() {
+        eprintln!("Warning: {}", warning);
+    }
+    if let Some(video) = vm.devices_mut().get_mut::<Framebuffer>() {
+        video.flush(&mut TerminalConsole::new());
+        if let Some((path, e)) = video.take_error() {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+        }
+        if let Some(path) = &options.screenshot
+            && let Err(e) = video.save(path)
+        {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+        }
+    }
 
-        if libc::tcsetattr(fd, libc::TCSANOW, &term) != 0 {
-            panic!("tcsetattr failed");
+    match result {
+        Ok(StepOutcome::Watchpoint(hit)) => {
+            let instruction = vm.peek(hit.pc);
+            eprintln!("{}: {}", hit, disassembler::disassemble(hit.pc, instruction, None));
+            std::process::exit(3);
+        }
+        Ok(StepOutcome::LimitReached(limit)) => {
+            let pc = vm.get_pc();
+            eprintln!("Stopped: {} at PC x{:04X}", limit, pc);
+            let recent = vm.recent_pcs();
+            if !recent.is_empty() {
+                eprintln!("Recent instructions:");
+            }
+            for pc in recent {
+                eprintln!("  x{:04X}  {}", pc, disassembler::disassemble(pc, vm.peek(pc), Some(&symbols)));
+            }
+            if let Some(calls) = vm.call_stack()
+                && calls.depth() > 0
+            {
+                eprintln!("Backtrace:\n{}", calls.backtrace(pc).format(Some(&symbols)));
+            }
+            std::process::exit(124);
+        }
+        Ok(_) => {}
+        Err(VMError::Fault { error, stack }) => {
+            eprintln!("VM error: {}\nBacktrace:\n{}", error, stack.format(Some(&symbols)));
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("VM error: {}", e);
+            std::process::exit(1);
         }
     }
 }
 
-fn restore_input_buffering() {
-    unsafe {
-        if let Some(ref original) = ORIGINAL_TIO {
-            if libc::tcsetattr(STDIN_FILENO, libc::TCSANOW, original) != 0 {
-                panic!("tcsetattr failed while restoring terminal");
+// Labels from the `.sym` files next to the images, when there are any.
+fn image_symbols(images: &[String]) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for image in images {
+        if let Ok(text) = fs::read_to_string(Path::new(image).with_extension("sym")) {
+            for (name, addr) in SymbolTable::parse(&text).iter() {
+                symbols.insert(name, addr);
             }
         }
     }
+    symbols
 }
 
-fn check_key() -> bool {
-    let stdin = stdin();
-
-    let mut readfds = FdSet::new();
-    let binding = stdin.as_fd();
-    readfds.insert(&binding);
-
-    let mut timeout = TimeVal::new(0, 0);
-
-    match select(
-        None,                 // ⚠️ modern nix infers nfds
-        Some(&mut readfds),
-        None,
-        None,
-        Some(&mut timeout),
-    ) {
-        Ok(n) => n > 0,
-        Err(_) => false,
-    }
-}
-
-#[derive(Debug, PartialEq, Eq)]
-#[repr(u16)]
-#[allow(dead_code)]
-pub enum Opcodes
-{
-    BR = 0, /* branch */
-    ADD = 1,    /* add  */
-    LD = 2,     /* load */
-    ST = 3,     /* store */
-    JSR = 4,    /* jump register */
-    AND = 5,    /* bitwise and */
-    LDR = 6,    /* load register */
-    STR = 7,    /* store register */
-    RTI = 8,    /* unused */
-    NOT = 9,    /* bitwise not */
-    LDI = 10,    /* load indirect */
-    STI = 11,    /* store indirect */
-    JMP = 12,    /* jump */
-    RES = 13,    /* reserved (unused) */
-    LEA = 14,    /* load effective address */
-    TRAP = 15   /* execute trap */
-}
-
-impl From<u16> for Opcodes {
-    fn from(value: u16) -> Self {
-        match value {
-            0 => Opcodes::BR,
-            1 => Opcodes::ADD,
-            2 => Opcodes::LD,
-            3 => Opcodes::ST,
-            4 => Opcodes::JSR,
-            5 => Opcodes::AND,
-            6 => Opcodes::LDR,
-            7 => Opcodes::STR,
-            8 => Opcodes::RTI,
-            9 => Opcodes::NOT,
-            10 => Opcodes::LDI,
-            11 => Opcodes::STI,
-            12 => Opcodes::JMP,
-            13 => Opcodes::RES,
-            14 => Opcodes::LEA,
-            15 => Opcodes::TRAP,
-            _ => panic!("Invalid opcode"),
+fn write_profile(profiler: &Profiler, symbols: &SymbolTable, options: &RunOptions) {
+    let mut outputs = Vec::new();
+    if let Some(path) = &options.profile {
+        outputs.push((path, profiler.report(Some(symbols), PROFILE_TOP)));
+    }
+    if let Some(path) = &options.profile_data {
+        let data = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => profiler.to_json(),
+            _ => profiler.to_csv(),
+        };
+        outputs.push((path, data));
+    }
+    for (path, contents) in outputs {
+        if let Err(e) = fs::write(path, contents) {
+            eprintln!("Failed to write {}: {}", path, e);
         }
     }
 }
 
-fn main() {
-    disable_input_buffering();
-    //Load arguments
-    let args: Vec<String> = env::args().collect();
+fn resume(vm: &mut VM, path: &str, check_program: bool) {
+    let snapshot = Snapshot::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if check_program && snapshot.program_hash != vm.program_hash() {
+        eprintln!("Warning: {} was taken with different images loaded", path);
+    }
+    if !snapshot.running {
+        eprintln!("{} is of a halted machine, nothing to resume", path);
+        std::process::exit(0);
+    }
+    if let Err(e) = vm.restore(&snapshot) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut vm = VM::new();
-    let Some(filename) = args.get(1) else {
-        println!("You must provide an obj file");
+// Like `VM::run_with_limits`, but saves a snapshot whenever one is
+// requested.
+fn run_until_halt(vm: &mut VM, snapshot_file: &str, limits: RunLimits) -> Result<StepOutcome, VMError> {
+    vm.run_with_hook(limits, |vm| {
+        if terminal::snapshot_requested() {
+            match vm.snapshot().save(snapshot_file) {
+                Ok(()) => eprintln!("Snapshot saved to {}", snapshot_file),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    })
+}
+
+fn attach_video(vm: &mut VM, video: Framebuffer) {
+    if let Err(e) = vm.attach_device(VIDEO_BASE..=VIDEO_END, video) {
+        eprintln!("{}", e);
         std::process::exit(1);
+    }
+}
+
+/// `asm <source> [-o <out.obj>]`: writes the object image and a `.sym`
+/// file next to it.
+fn assemble_command(args: &[String]) {
+    let (source_path, output) = match args {
+        [source] => (source, Path::new(source).with_extension("obj")),
+        [source, flag, output] if flag == "-o" => (source, output.into()),
+        _ => usage(),
     };
 
-    vm.read_image(filename);
+    let source = fs::read_to_string(source_path).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", source_path, e);
+        std::process::exit(1);
+    });
 
+    let assembly = match assembler::assemble(&source) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            eprintln!("{}:{}:{}: {}", source_path, e.line, e.column, e.message);
+            std::process::exit(1);
+        }
+    };
 
-    if args.len() < 2 {
-        println!("lc3 [image-file] ... \n");
-        std::process::exit(2);
+    let sym_path = output.with_extension("sym");
+    if let Err(e) = fs::write(&output, assembly.to_obj_bytes())
+        .and_then(|_| fs::write(&sym_path, assembly.symbols.to_sym_string()))
+    {
+        eprintln!("Failed to write {}: {}", output.display(), e);
+        std::process::exit(1);
     }
+}
 
-    vm.turn_on();
+/// `disasm <image.obj> [--symbols <file.sym>]`: prints address, raw word
+/// and decoded text. A `.sym` file next to the image is used if present.
+fn disassemble_command(args: &[String]) {
+    let (image_path, sym_path) = match args {
+        [image] => (image, Path::new(image).with_extension("sym")),
+        [image, flag, sym] if flag == "--symbols" => (image, sym.into()),
+        _ => usage(),
+    };
 
-    while vm.is_running() {
+    let image = Image::read(image_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
 
-        let curr_pc = vm.get_pc();
-        let instruction: u16 = vm.mem_read(curr_pc);
-        let operation = Opcodes::from(instruction >> 12);
-        
-        vm.advance_pc();
-        // For debugging purposes:
-        // println!(
-        //     "PC: {:04X}  INSTR: {:04X}  OP: {:X}",
-        //     vm.get_pc(),
-        //     instruction,
-        //     instruction >> 12
-        // );
+    let symbols = fs::read_to_string(&sym_path).ok().map(|text| SymbolTable::parse(&text));
+    let mut out = io::stdout().lock();
+    for line in disassembler::disassemble_words(image.origin, &image.words, symbols.as_ref()) {
+        // Stop quietly when the reader goes away (e.g. piped into `head`).
+        if writeln!(out, "{}", line).is_err() {
+            break;
+        }
+    }
+}
 
-        match operation {
-            Opcodes::ADD => {
-                vm.add(instruction);
-            }
-            Opcodes::AND => {
-                vm.and(instruction);
-            }
-            Opcodes::NOT => {
-                vm.not(instruction);
-            }
-            Opcodes::BR => {
-                vm.branch(instruction);
-            }
-            Opcodes::JMP => {
-                vm.jmp(instruction);
-            }
-            Opcodes::JSR => {
-                vm.jump(instruction);
-            }
-            Opcodes::LD => {
-                vm.load(instruction);
-            }
-            Opcodes::LDI => {
-                vm.ldi(instruction);
-            }
-            Opcodes::LDR => {
-                vm.ldr(instruction);
-            }
-            Opcodes::LEA => {
-                vm.lea(instruction);
-            }
-            Opcodes::ST => {
-                vm.store(instruction);
-            }
-            Opcodes::STI => {
-                vm.store_indirect(instruction);
-            }
-            Opcodes::STR => {
-                vm.store_register(instruction);
-            }
-            Opcodes::TRAP => {
-                let curr_pc = vm.get_pc();
-                vm.set_reg(Register::R7 as usize, curr_pc);
-                vm.execute_trap_routine(instruction);
-            }
-            Opcodes::RES => {}
-            _ => {
-                println!("Unknown operation code");
-                std::process::abort();
-            }
+/// `debug <image.obj> [--symbols <file.sym>] [--video]`: interactive
+/// debugger on stdin/stdout. `--video` attaches a headless display for the
+/// `screenshot` command.
+fn debug_command(args: &[String]) {
+    let video = args.iter().any(|arg| arg == "--video");
+    let args: Vec<String> = args.iter().filter(|arg| *arg != "--video").cloned().collect();
+    let (image_path, sym_path) = match args.as_slice() {
+        [image] => (image, Path::new(image).with_extension("sym")),
+        [image, flag, sym] if flag == "--symbols" => (image, sym.into()),
+        _ => usage(),
+    };
+
+    let mut vm = VM::new();
+    if let Err(e) = vm.read_image(image_path) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if video {
+        attach_video(&mut vm, Framebuffer::new());
+    }
+    let symbols = fs::read_to_string(&sym_path)
+        .map(|text| SymbolTable::parse(&text))
+        .unwrap_or_default();
+
+    let mut debugger = Debugger::new(vm, symbols);
+    if let Err(e) = debugger.repl(io::stdin().lock(), &mut io::stdout()) {
+        eprintln!("Debugger IO error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// `gdb <image.obj> [--listen <host:port> | --unix <path>]`: waits for a
+/// single GDB remote protocol client, `127.0.0.1:1234` by default.
+fn gdb_command(args: &[String]) {
+    let (image_path, listen) = match args {
+        [image] => (image, None),
+        [image, flag, addr] if flag == "--listen" || flag == "--unix" => (image, Some((flag, addr))),
+        _ => usage(),
+    };
+
+    let mut vm = VM::new();
+    if let Err(e) = vm.read_image(image_path) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let result = match listen {
+        Some((flag, path)) if flag == "--unix" => UnixListener::bind(path).and_then(|listener| {
+            eprintln!("Waiting for GDB on {}", path);
+            let (mut conn, _) = listener.accept()?;
+            GdbStub::new(&mut vm).serve(&mut conn)
+        }),
+        _ => {
+            let addr = listen.map_or("127.0.0.1:1234", |(_, addr)| addr.as_str());
+            TcpListener::bind(addr).and_then(|listener| {
+                eprintln!("Waiting for GDB on {}", addr);
+                let (mut conn, _) = listener.accept()?;
+                // Packets are tiny request/reply pairs, Nagle only adds latency.
+                conn.set_nodelay(true)?;
+                GdbStub::new(&mut vm).serve(&mut conn)
+            })
         }
+    };
 
-        restore_input_buffering();
+    if let Err(e) = result {
+        eprintln!("GDB server error: {}", e);
+        std::process::exit(1);
+    }
 }
-}
\ No newline at end of file
@@ -0,0 +1,39 @@
+/// Parses a number typed by the user: `x3000`, `0x3000`, `#12` or plain
+/// decimal.
+pub fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix(['x', 'X'])) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.strip_prefix('#').unwrap_or(text).parse().ok(),
+    }
+}
+
+/// A number that fits in one word, e.g. an address.
+pub fn parse_word(text: &str) -> Option<u16> {
+    parse_number(text).and_then(|n| u16::try_from(n).ok())
+}
+
+/// A word value, also taking negative decimals such as `#-1` as two's
+/// complement.
+pub fn parse_value(text: &str) -> Option<u16> {
+    match text.strip_prefix('#').unwrap_or(text).strip_prefix('-') {
+        Some(magnitude) => magnitude.parse::<u16>().ok().filter(|&m| m <= 0x8000).map(u16::wrapping_neg),
+        None => parse_word(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_forms() {
+        for text in ["x3000", "X3000", "0x3000", "#12288", "12288"] {
+            assert_eq!(parse_word(text), Some(0x3000), "{}", text);
+        }
+        assert_eq!(parse_number("4000000000"), Some(4_000_000_000));
+        assert_eq!(parse_word("x10000"), None);
+        assert_eq!(parse_word("x"), None);
+        assert_eq!((parse_value("#-1"), parse_value("-32768")), (Some(0xFFFF), Some(0x8000)));
+        assert_eq!(parse_value("-32769"), None);
+    }
+}
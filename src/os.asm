@@ -0,0 +1,198 @@
+; Minimal LC-3 operating system for running TRAPs from memory.
+;
+; Provides the standard service routines (GETC, OUT, PUTS, IN, PUTSP,
+; HALT) on top of the keyboard, display and machine control registers.
+; Routines follow the classic convention: TRAP saves the return address
+; in R7, the routine preserves every other register and returns with RET.
+
+        .ORIG x0000
+
+; Trap vector table, x0000-x00FF. os_image() points the unused entries
+; at BAD_TRAP.
+        .BLKW x20
+        .FILL TRAP_GETC          ; x20
+        .FILL TRAP_OUT           ; x21
+        .FILL TRAP_PUTS          ; x22
+        .FILL TRAP_IN            ; x23
+        .FILL TRAP_PUTSP         ; x24
+        .FILL TRAP_HALT          ; x25
+        .BLKW xDA
+
+; Interrupt vector table, x0100-x01FF. os_image() points the unused
+; entries at BAD_INTERRUPT.
+        .FILL PRIVILEGE_EXCEPTION        ; x00
+        .FILL ILLEGAL_OPCODE_EXCEPTION   ; x01
+        .BLKW xFE
+
+; Service routines start at x0200.
+
+; GETC: read one character into R0, without echo.
+TRAP_GETC
+        LDI R0, OS_KBSR
+        BRzp TRAP_GETC
+        LDI R0, OS_KBDR
+        RET
+
+; OUT: write the character in R0.
+TRAP_OUT
+        ST R1, OUT_SAVE_R1
+OUT_WAIT
+        LDI R1, OS_DSR
+        BRzp OUT_WAIT
+        STI R0, OS_DDR
+        LD R1, OUT_SAVE_R1
+        RET
+OUT_SAVE_R1 .BLKW 1
+
+; PUTS: write the zero-terminated string of one character per word at R0.
+TRAP_PUTS
+        ST R0, PUTS_SAVE_R0
+        ST R1, PUTS_SAVE_R1
+        ST R2, PUTS_SAVE_R2
+        ADD R1, R0, #0
+PUTS_LOOP
+        LDR R0, R1, #0
+        BRz PUTS_DONE
+PUTS_WAIT
+        LDI R2, OS_DSR
+        BRzp PUTS_WAIT
+        STI R0, OS_DDR
+        ADD R1, R1, #1
+        BR PUTS_LOOP
+PUTS_DONE
+        LD R0, PUTS_SAVE_R0
+        LD R1, PUTS_SAVE_R1
+        LD R2, PUTS_SAVE_R2
+        RET
+PUTS_SAVE_R0 .BLKW 1
+PUTS_SAVE_R1 .BLKW 1
+PUTS_SAVE_R2 .BLKW 1
+
+; IN: prompt, read one character into R0 and echo it.
+TRAP_IN
+        ST R1, IN_SAVE_R1
+        ST R2, IN_SAVE_R2
+        LEA R1, IN_PROMPT
+IN_PROMPT_LOOP
+        LDR R2, R1, #0
+        BRz IN_READ
+IN_PROMPT_WAIT
+        LDI R0, OS_DSR
+        BRzp IN_PROMPT_WAIT
+        STI R2, OS_DDR
+        ADD R1, R1, #1
+        BR IN_PROMPT_LOOP
+IN_READ
+        LDI R0, OS_KBSR
+        BRzp IN_READ
+        LDI R0, OS_KBDR
+IN_ECHO_WAIT
+        LDI R1, OS_DSR
+        BRzp IN_ECHO_WAIT
+        STI R0, OS_DDR
+        LD R1, IN_SAVE_R1
+        LD R2, IN_SAVE_R2
+        ADD R0, R0, #0
+        RET
+IN_SAVE_R1 .BLKW 1
+IN_SAVE_R2 .BLKW 1
+IN_PROMPT .STRINGZ "Enter a character: "
+
+; PUTSP: write the zero-terminated string of two characters per word at
+; R0, low byte first. A zero high byte is skipped.
+TRAP_PUTSP
+        ST R7, PUTSP_SAVE_R7
+        ST R0, PUTSP_SAVE_R0
+        ST R1, PUTSP_SAVE_R1
+        ST R2, PUTSP_SAVE_R2
+        ST R3, PUTSP_SAVE_R3
+        ST R4, PUTSP_SAVE_R4
+        ADD R1, R0, #0
+PUTSP_LOOP
+        LDR R2, R1, #0
+        BRz PUTSP_DONE
+        LD R0, LOW_BYTE_MASK
+        AND R0, R2, R0
+        JSR PUTSP_WRITE
+        ; Shift the high byte down by collecting the top 8 bits.
+        AND R0, R0, #0
+        AND R3, R3, #0
+        ADD R3, R3, #8
+PUTSP_SHIFT
+        ADD R0, R0, R0
+        ADD R2, R2, #0
+        BRzp PUTSP_NO_CARRY
+        ADD R0, R0, #1
+PUTSP_NO_CARRY
+        ADD R2, R2, R2
+        ADD R3, R3, #-1
+        BRp PUTSP_SHIFT
+        ADD R0, R0, #0
+        BRz PUTSP_NEXT
+        JSR PUTSP_WRITE
+PUTSP_NEXT
+        ADD R1, R1, #1
+        BR PUTSP_LOOP
+PUTSP_DONE
+        LD R0, PUTSP_SAVE_R0
+        LD R1, PUTSP_SAVE_R1
+        LD R2, PUTSP_SAVE_R2
+        LD R3, PUTSP_SAVE_R3
+        LD R4, PUTSP_SAVE_R4
+        LD R7, PUTSP_SAVE_R7
+        RET
+; Writes R0 to the display, called with JSR from the loop above.
+PUTSP_WRITE
+        LDI R4, OS_DSR
+        BRzp PUTSP_WRITE
+        STI R0, OS_DDR
+        RET
+PUTSP_SAVE_R0 .BLKW 1
+PUTSP_SAVE_R1 .BLKW 1
+PUTSP_SAVE_R2 .BLKW 1
+PUTSP_SAVE_R3 .BLKW 1
+PUTSP_SAVE_R4 .BLKW 1
+PUTSP_SAVE_R7 .BLKW 1
+LOW_BYTE_MASK .FILL x00FF
+
+; HALT: stop the clock by clearing bit 15 of the MCR.
+TRAP_HALT
+        LDI R0, OS_MCR
+        LD R1, CLOCK_MASK
+        AND R0, R0, R1
+        STI R0, OS_MCR
+        BR TRAP_HALT
+CLOCK_MASK .FILL x7FFF
+
+; Unknown trap vectors report the problem and halt.
+BAD_TRAP
+        LEA R0, BAD_TRAP_MSG
+        PUTS
+        HALT
+BAD_TRAP_MSG .STRINGZ "\nBad TRAP executed, halting.\n"
+
+; Exceptions report the problem and halt.
+PRIVILEGE_EXCEPTION
+        LEA R0, PRIVILEGE_MSG
+        PUTS
+        HALT
+PRIVILEGE_MSG .STRINGZ "\nPrivilege mode violation, halting.\n"
+
+ILLEGAL_OPCODE_EXCEPTION
+        LEA R0, ILLEGAL_OPCODE_MSG
+        PUTS
+        HALT
+ILLEGAL_OPCODE_MSG .STRINGZ "\nIllegal opcode, halting.\n"
+
+; Unexpected interrupts are ignored.
+BAD_INTERRUPT
+        RTI
+
+; Device register addresses.
+OS_KBSR .FILL xFE00
+OS_KBDR .FILL xFE02
+OS_DSR  .FILL xFE04
+OS_DDR  .FILL xFE06
+OS_MCR  .FILL xFFFE
+
+        .END
@@ -0,0 +1,104 @@
+use crate::assembler;
+use crate::interrupt::INTERRUPT_VECTOR_TABLE;
+use crate::loader::Image;
+
+/// Source of the bundled operating system. It fills the trap and interrupt
+/// vector tables and implements the standard service routines using the
+/// keyboard, display and machine control registers.
+pub const OS_SOURCE: &str = include_str!("os.asm");
+
+const TRAP_VECTOR_TABLE: u16 = 0x0000;
+
+/// Assembles the bundled operating system into a loadable image. Vector
+/// table entries the source leaves empty go to BAD_TRAP or BAD_INTERRUPT.
+pub fn os_image() -> Image {
+    let assembly = assembler::assemble(OS_SOURCE).expect("bundled OS source must assemble");
+    let mut words = assembly.words;
+    for (table, handler) in [(TRAP_VECTOR_TABLE, "BAD_TRAP"), (INTERRUPT_VECTOR_TABLE, "BAD_INTERRUPT")] {
+        let handler = assembly.symbols.address_of(handler).expect("bundled OS defines its default handlers");
+        let start = (table - assembly.origin) as usize;
+        for entry in &mut words[start..start + 0x100] {
+            if *entry == 0 {
+                *entry = handler;
+            }
+        }
+    }
+    Image { name: "lc3os".to_string(), origin: assembly.origin, words }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::{StepOutcome, TrapMode, VM};
+
+    fn boot(program: &str, input: &[u8]) -> (VM, BufferConsole) {
+        let console = BufferConsole::new(input);
+        let mut vm = VM::with_console(console.clone());
+        vm.load_image(&os_image()).unwrap();
+        let assembly = assembler::assemble(program).unwrap();
+        vm.load_image(&Image { name: "test".to_string(), origin: assembly.origin, words: assembly.words }).unwrap();
+        vm.set_trap_mode(TrapMode::Memory);
+        (vm, console)
+    }
+
+    #[test]
+    fn test_vector_tables() {
+        let image = os_image();
+        let symbols = assembler::assemble(OS_SOURCE).unwrap().symbols;
+        let address = |name| symbols.address_of(name).unwrap();
+        assert_eq!(address("TRAP_GETC"), 0x0200);
+        assert_eq!(image.words[0x00], address("BAD_TRAP"));
+        assert_eq!(image.words[0x25], address("TRAP_HALT"));
+        assert_eq!(image.words[0xFF], address("BAD_TRAP"));
+        assert_eq!(image.words[0x101], address("ILLEGAL_OPCODE_EXCEPTION"));
+        assert_eq!(image.words[0x102], address("BAD_INTERRUPT"));
+        assert_eq!(image.words[0x1FF], address("BAD_INTERRUPT"));
+    }
+
+    #[test]
+    fn test_os_services_standard_traps() {
+        let program = "\
+            .ORIG x3000
+            GETC
+            OUT
+            LEA R0, MSG
+            PUTS
+            LEA R0, PACKED
+            PUTSP
+            IN
+            ST R0, KEY
+            HALT
+            KEY .BLKW 1
+            MSG .STRINGZ \"ok\"
+            PACKED .FILL x6261
+            .FILL x0063
+            .FILL 0
+            .END";
+        let (mut vm, console) = boot(program, b"xy");
+
+        assert_eq!(vm.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), b"xokabcEnter a character: y");
+        assert_eq!(vm.peek(0x3009), 'y' as u16);
+    }
+
+    #[test]
+    fn test_custom_and_unknown_trap_vectors() {
+        let program = "\
+            .ORIG x3000
+            LD R1, HANDLER
+            STI R1, VECTOR
+            TRAP x40
+            TRAP x41
+            HALT
+            HANDLER .FILL x4000
+            VECTOR .FILL x0040
+            .END";
+        let (mut vm, console) = boot(program, b"");
+        vm.load_image(&Image { name: "handler".to_string(), origin: 0x4000, words: vec![0x16E1, 0xC1C0] }).unwrap(); // ADD R3, R3, #1; RET
+
+        assert_eq!(vm.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(vm.read_reg(3), 1);
+        assert_eq!(console.output(), b"\nBad TRAP executed, halting.\n");
+    }
+}
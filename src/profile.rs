@@ -0,0 +1,241 @@
+use std::fmt::Write as _;
+use crate::disassembler;
+use crate::symbols::SymbolTable;
+use crate::trace::Effect;
+use crate::vm::{Opcodes, TrapCodes};
+
+const MEMORY_SIZE: usize = 1 << 16;
+
+/// Execution counts gathered while profiling: per PC, per opcode, per
+/// TRAP vector, and loads/stores per address.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    executed: Vec<u64>,
+    instructions: Vec<u16>, /* last instruction seen at each PC, for the report */
+    opcodes: [u64; 16],
+    traps: [u64; 256],
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    retired: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            executed: vec![0; MEMORY_SIZE],
+            instructions: vec![0; MEMORY_SIZE],
+            opcodes: [0; 16],
+            traps: [0; 256],
+            reads: vec![0; MEMORY_SIZE],
+            writes: vec![0; MEMORY_SIZE],
+            retired: 0,
+        }
+    }
+
+    /// Counts one retired instruction and the memory it accessed.
+    pub fn record(&mut self, pc: u16, instruction: u16, effects: &[Effect]) {
+        self.retired += 1;
+        self.executed[pc as usize] += 1;
+        self.instructions[pc as usize] = instruction;
+        self.opcodes[(instruction >> 12) as usize] += 1;
+        if instruction >> 12 == Opcodes::TRAP as u16 {
+            self.traps[(instruction & 0xFF) as usize] += 1;
+        }
+        for effect in effects {
+            match *effect {
+                Effect::MemRead { addr, .. } => self.reads[addr as usize] += 1,
+                Effect::MemWrite { addr, .. } => self.writes[addr as usize] += 1,
+                Effect::RegWrite { .. } => {}
+            }
+        }
+    }
+
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    pub fn executed(&self, pc: u16) -> u64 {
+        self.executed[pc as usize]
+    }
+
+    pub fn opcode_count(&self, opcode: Opcodes) -> u64 {
+        self.opcodes[opcode as usize]
+    }
+
+    pub fn trap_count(&self, vector: u8) -> u64 {
+        self.traps[vector as usize]
+    }
+
+    pub fn reads(&self, addr: u16) -> u64 {
+        self.reads[addr as usize]
+    }
+
+    pub fn writes(&self, addr: u16) -> u64 {
+        self.writes[addr as usize]
+    }
+
+    /// Human readable report: the `top` hottest instructions with their
+    /// disassembly, then opcode, TRAP and memory counts, busiest first.
+    pub fn report(&self, symbols: Option<&SymbolTable>, top: usize) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Instructions retired: {}", self.retired);
+
+        let _ = writeln!(out, "\nHot spots:\n{:>10} {:>6}  address  instruction", "count", "%");
+        for (pc, count) in busiest(&self.executed).take(top) {
+            let text = disassembler::disassemble(pc, self.instructions[pc as usize], symbols);
+            let label = symbols.and_then(|symbols| symbols.name_at(pc)).map(|name| format!("{}: ", name));
+            let _ = writeln!(
+                out,
+                "{:>10} {:>5.1}%  x{:04X}    {}{}",
+                count,
+                self.percent(count),
+                pc,
+                label.unwrap_or_default(),
+                text
+            );
+        }
+
+        let _ = writeln!(out, "\nOpcodes:");
+        for (code, count) in busiest(&self.opcodes) {
+            let name = Opcodes::try_from(code).map_or("?", |op| op.name());
+            let _ = writeln!(out, "{:>10} {:>5.1}%  {}", count, self.percent(count), name);
+        }
+
+        let traps: Vec<_> = busiest(&self.traps).collect();
+        if !traps.is_empty() {
+            let _ = writeln!(out, "\nTraps:");
+            for (vector, count) in traps {
+                let name = TrapCodes::try_from(vector).map_or("", |trap| trap.name());
+                let _ = writeln!(out, "{:>10}         x{:02X} {}", count, vector, name);
+            }
+        }
+
+        let accesses: Vec<u64> = self.reads.iter().zip(&self.writes).map(|(reads, writes)| reads + writes).collect();
+        let _ = writeln!(out, "\nMemory:\n{:>10} {:>10}  address", "reads", "writes");
+        for (addr, _) in busiest(&accesses).take(top) {
+            let label = symbols.and_then(|symbols| symbols.name_at(addr)).map(|name| format!(" ({})", name));
+            let _ = writeln!(
+                out,
+                "{:>10} {:>10}  x{:04X}{}",
+                self.reads[addr as usize],
+                self.writes[addr as usize],
+                addr,
+                label.unwrap_or_default()
+            );
+        }
+        out
+    }
+
+    /// All non-zero counts as `kind,key,count` rows, where kind is `pc`,
+    /// `opcode`, `trap`, `read` or `write`.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("kind,key,count\n");
+        for (kind, key, count) in self.rows() {
+            let _ = writeln!(out, "{},{},{}", kind, key, count);
+        }
+        out
+    }
+
+    /// The same counts as `to_csv`, as a JSON object keyed by kind, plus
+    /// the `retired` total.
+    pub fn to_json(&self) -> String {
+        let mut out = format!("{{\n  \"retired\": {}", self.retired);
+        let rows = self.rows();
+        for kind in ["pc", "opcode", "trap", "read", "write"] {
+            let entries: Vec<String> = rows
+                .iter()
+                .filter(|(row_kind, _, _)| *row_kind == kind)
+                .map(|(_, key, count)| format!("\"{}\": {}", key, count))
+                .collect();
+            let _ = write!(out, ",\n  \"{}\": {{{}}}", kind, entries.join(", "));
+        }
+        out.push_str("\n}\n");
+        out
+    }
+
+    fn rows(&self) -> Vec<(&'static str, String, u64)> {
+        let mut rows = Vec::new();
+        let nonzero = |counts: &[u64]| -> Vec<(usize, u64)> {
+            counts.iter().copied().enumerate().filter(|&(_, count)| count != 0).collect()
+        };
+        for (pc, count) in nonzero(&self.executed) {
+            rows.push(("pc", format!("x{:04X}", pc), count));
+        }
+        for (code, count) in nonzero(&self.opcodes) {
+            let name = Opcodes::try_from(code as u16).map_or("?", |op| op.name());
+            rows.push(("opcode", name.to_string(), count));
+        }
+        for (vector, count) in nonzero(&self.traps) {
+            rows.push(("trap", format!("x{:02X}", vector), count));
+        }
+        for (addr, count) in nonzero(&self.reads) {
+            rows.push(("read", format!("x{:04X}", addr), count));
+        }
+        for (addr, count) in nonzero(&self.writes) {
+            rows.push(("write", format!("x{:04X}", addr), count));
+        }
+        rows
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.retired.max(1) as f64
+    }
+}
+
+// Non-zero entries by descending count, then ascending index.
+fn busiest(counts: &[u64]) -> impl Iterator<Item = (u16, u64)> {
+    let mut entries: Vec<(u16, u64)> =
+        counts.iter().enumerate().filter(|&(_, &count)| count != 0).map(|(i, &count)| (i as u16, count)).collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    entries.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn profiled(source: &str) -> (Profiler, SymbolTable) {
+        let (mut vm, symbols) = VM::from_source(source);
+        vm.set_profiler(Some(Profiler::new()));
+        vm.run().unwrap();
+        (vm.set_profiler(None).unwrap(), symbols)
+    }
+
+    #[test]
+    fn test_counts_loop() {
+        let (profile, _) = profiled(
+            ".ORIG x3000\nLD R1, N\nLOOP ADD R1, R1, #-1\nST R1, N\nBRp LOOP\nOUT\nHALT\nN .FILL #3\n.END",
+        );
+        assert_eq!(profile.retired(), 1 + 3 * 3 + 2);
+        assert_eq!(profile.executed(0x3001), 3);
+        assert_eq!(profile.opcode_count(Opcodes::ADD), 3);
+        assert_eq!(profile.opcode_count(Opcodes::TRAP), 2);
+        assert_eq!((profile.trap_count(0x21), profile.trap_count(0x25)), (1, 1));
+        assert_eq!((profile.reads(0x3006), profile.writes(0x3006)), (1, 3));
+    }
+
+    #[test]
+    fn test_report_and_dumps() {
+        let (profile, symbols) =
+            profiled(".ORIG x3000\nAND R0, R0, #0\nLOOP ADD R0, R0, #1\nADD R1, R0, #-2\nBRn LOOP\nHALT\n.END");
+
+        let report = profile.report(Some(&symbols), 3);
+        let hot = report.lines().skip_while(|line| !line.starts_with("Hot spots")).nth(2).unwrap();
+        assert!(hot.ends_with("x3001    LOOP: ADD R0, R0, #1"), "{}", hot);
+        assert!(report.contains("x25 HALT"));
+
+        let csv = profile.to_csv();
+        assert!(csv.starts_with("kind,key,count\npc,x3000,1\npc,x3001,2\n"));
+        assert!(csv.contains("opcode,ADD,4\n"));
+        let json = profile.to_json();
+        assert!(json.contains("\"retired\": 8"));
+        assert!(json.contains("\"trap\": {\"x25\": 1}"));
+    }
+}
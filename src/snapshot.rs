@@ -0,0 +1,299 @@
+use std::fs;
+use thiserror::Error;
+use crate::interrupt::{ExceptionPolicy, Interrupt};
+use crate::vm::TrapMode;
+
+/// First bytes of every snapshot file.
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"LC3SNAP\0";
+/// Bumped whenever the layout below changes.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const MEMORY_WORDS: usize = 1 << 16;
+
+const FLAG_RUNNING: u16 = 1 << 0;
+const FLAG_TRAPS_IN_MEMORY: u16 = 1 << 1;
+const FLAG_VECTOR_PRIVILEGE: u16 = 1 << 2;
+const FLAG_VECTOR_ILLEGAL_OPCODE: u16 = 1 << 3;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Failed to access snapshot {name}: {source}")]
+    Io { name: String, source: std::io::Error },
+    #[error("Not an LC-3 snapshot")]
+    BadMagic,
+    #[error("Unsupported snapshot version {0}, expected {SNAPSHOT_VERSION}")]
+    UnsupportedVersion(u16),
+    #[error("Snapshot is truncated")]
+    Truncated,
+    #[error("Snapshot has state for device {0}, which is not attached")]
+    MissingDevice(String),
+    #[error("Snapshot memory has {0} words, expected 65536")]
+    MemorySize(usize),
+    #[error("Device name {0} is longer than 255 bytes")]
+    DeviceNameTooLong(String),
+}
+
+/// Complete machine state, as taken by `VM::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: [u16; 10], /* R0-R7, PC, COND */
+    pub psr: u16,
+    pub saved_usp: u16,
+    pub saved_ssp: u16,
+    pub running: bool,
+    pub trap_mode: TrapMode,
+    pub exception_policies: [ExceptionPolicy; 2],
+    pub pending_interrupts: Vec<Interrupt>,
+    pub memory: Vec<u16>,                /* all 64K words */
+    pub devices: Vec<(String, Vec<u16>)>, /* device name and saved state */
+    pub program_hash: u64,               /* hash of the images that were loaded */
+}
+
+impl Snapshot {
+    /// Serialises the snapshot. All values are big-endian, like `.obj`
+    /// files; memory is stored as runs of non-zero words.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SnapshotError> {
+        if self.memory.len() != MEMORY_WORDS {
+            return Err(SnapshotError::MemorySize(self.memory.len()));
+        }
+        if let Some((name, _)) = self.devices.iter().find(|(name, _)| name.len() > u8::MAX as usize) {
+            return Err(SnapshotError::DeviceNameTooLong(name.clone()));
+        }
+        let mut out = SNAPSHOT_MAGIC.to_vec();
+        put_u16(&mut out, SNAPSHOT_VERSION);
+        put_u16(&mut out, self.flags());
+        out.extend_from_slice(&self.program_hash.to_be_bytes());
+        for &word in self.registers.iter().chain(&[self.psr, self.saved_usp, self.saved_ssp]) {
+            put_u16(&mut out, word);
+        }
+
+        put_u16(&mut out, self.pending_interrupts.len() as u16);
+        for interrupt in &self.pending_interrupts {
+            out.extend_from_slice(&[interrupt.vector, interrupt.priority]);
+        }
+
+        let runs = memory_runs(&self.memory);
+        out.extend_from_slice(&(runs.len() as u32).to_be_bytes());
+        for (start, words) in runs {
+            put_u16(&mut out, start);
+            put_u16(&mut out, words.len() as u16);
+            put_words(&mut out, words);
+        }
+
+        put_u16(&mut out, self.devices.len() as u16);
+        for (name, state) in &self.devices {
+            out.push(name.len() as u8);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&(state.len() as u32).to_be_bytes());
+            put_words(&mut out, state);
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut input = Reader { bytes };
+        if input.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = input.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let flags = input.u16()?;
+        let program_hash = u64::from_be_bytes(input.take(8)?.try_into().unwrap());
+        let mut registers = [0; 10];
+        for register in &mut registers {
+            *register = input.u16()?;
+        }
+        let (psr, saved_usp, saved_ssp) = (input.u16()?, input.u16()?, input.u16()?);
+
+        let mut pending_interrupts = Vec::new();
+        for _ in 0..input.u16()? {
+            let pair = input.take(2)?;
+            pending_interrupts.push(Interrupt { vector: pair[0], priority: pair[1] });
+        }
+
+        let mut memory = vec![0; MEMORY_WORDS];
+        for _ in 0..input.u32()? {
+            let start = input.u16()? as usize;
+            let len = input.u16()? as usize;
+            let words = input.words(len)?;
+            memory
+                .get_mut(start..start + len)
+                .ok_or(SnapshotError::Truncated)?
+                .copy_from_slice(&words);
+        }
+
+        let mut devices = Vec::new();
+        for _ in 0..input.u16()? {
+            let len = input.take(1)?[0] as usize;
+            let name = String::from_utf8_lossy(input.take(len)?).into_owned();
+            let count = input.u32()? as usize;
+            devices.push((name, input.words(count)?));
+        }
+
+        let policy = |flag| if flags & flag != 0 { ExceptionPolicy::Vector } else { ExceptionPolicy::ReturnError };
+        Ok(Snapshot {
+            registers,
+            psr,
+            saved_usp,
+            saved_ssp,
+            running: flags & FLAG_RUNNING != 0,
+            trap_mode: if flags & FLAG_TRAPS_IN_MEMORY != 0 { TrapMode::Memory } else { TrapMode::Native },
+            exception_policies: [policy(FLAG_VECTOR_PRIVILEGE), policy(FLAG_VECTOR_ILLEGAL_OPCODE)],
+            pending_interrupts,
+            memory,
+            devices,
+            program_hash,
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_bytes()?).map_err(|source| SnapshotError::Io { name: path.to_string(), source })
+    }
+
+    pub fn load(path: &str) -> Result<Snapshot, SnapshotError> {
+        let bytes = fs::read(path).map_err(|source| SnapshotError::Io { name: path.to_string(), source })?;
+        Snapshot::from_bytes(&bytes)
+    }
+
+    fn flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.running {
+            flags |= FLAG_RUNNING;
+        }
+        if self.trap_mode == TrapMode::Memory {
+            flags |= FLAG_TRAPS_IN_MEMORY;
+        }
+        if self.exception_policies[0] == ExceptionPolicy::Vector {
+            flags |= FLAG_VECTOR_PRIVILEGE;
+        }
+        if self.exception_policies[1] == ExceptionPolicy::Vector {
+            flags |= FLAG_VECTOR_ILLEGAL_OPCODE;
+        }
+        flags
+    }
+}
+
+/// Starting value for `hash_words`.
+pub const HASH_SEED: u64 = 0xCBF2_9CE4_8422_2325;
+
+/// FNV-1a, used to tell whether a snapshot belongs to the loaded program.
+pub fn hash_words(mut hash: u64, words: &[u16]) -> u64 {
+    for byte in words.iter().flat_map(|word| word.to_be_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+// Maximal runs of non-zero words, split so each length fits in a u16.
+fn memory_runs(memory: &[u16]) -> Vec<(u16, &[u16])> {
+    let mut runs = Vec::new();
+    let mut addr = 0;
+    while addr < memory.len() {
+        if memory[addr] == 0 {
+            addr += 1;
+            continue;
+        }
+        let start = addr;
+        while addr < memory.len() && memory[addr] != 0 && addr - start < u16::MAX as usize {
+            addr += 1;
+        }
+        runs.push((start as u16, &memory[start..addr]));
+    }
+    runs
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_words(out: &mut Vec<u8>, words: &[u16]) {
+    for &word in words {
+        put_u16(out, word);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn words(&mut self, count: usize) -> Result<Vec<u16>, SnapshotError> {
+        let bytes = self.take(count.checked_mul(2).ok_or(SnapshotError::Truncated)?)?;
+        Ok(bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::{StepOutcome, VM};
+
+    #[test]
+    fn test_snapshot_round_trip_resumes_execution() {
+        let console = BufferConsole::new(b"");
+        let mut vm = VM::with_console(console.clone());
+        vm.mem_write(0x3000, 0x1021); // ADD R0, R0, #1
+        vm.mem_write(0x3001, 0xF021); // OUT
+        vm.mem_write(0x3002, 0xF025); // HALT
+        vm.mem_write(0xFE0A, 50); // timer reload
+        vm.set_reg(0, 'a' as u16);
+        vm.turn_on();
+        vm.step().unwrap();
+
+        let bytes = vm.snapshot().to_bytes().unwrap();
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot, vm.snapshot());
+        vm.restore(&snapshot).unwrap();
+        assert!(vm.recent_pcs().is_empty());
+
+        let mut resumed = VM::with_console(console.clone());
+        resumed.restore(&snapshot).unwrap();
+        assert_eq!(resumed.peek(0xFE0A), 50);
+        assert_eq!(resumed.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), b"b");
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        assert!(matches!(Snapshot::from_bytes(b"not a snapshot"), Err(SnapshotError::BadMagic)));
+
+        let vm = VM::with_console(BufferConsole::new(b""));
+        let mut snapshot = vm.snapshot();
+        snapshot.devices.push(("x".repeat(256), Vec::new()));
+        assert!(matches!(snapshot.to_bytes(), Err(SnapshotError::DeviceNameTooLong(_))));
+        snapshot.memory.pop();
+        assert!(matches!(snapshot.to_bytes(), Err(SnapshotError::MemorySize(0xFFFF))));
+        snapshot.devices.pop();
+        snapshot.registers[0] = 7;
+        let mut target = VM::with_console(BufferConsole::new(b""));
+        assert!(matches!(target.restore(&snapshot), Err(SnapshotError::MemorySize(0xFFFF))));
+        assert_eq!(target.read_reg(0), 0);
+
+        let mut bytes = vm.snapshot().to_bytes().unwrap();
+        bytes.truncate(bytes.len() - 1);
+        assert!(matches!(Snapshot::from_bytes(&bytes), Err(SnapshotError::Truncated)));
+        bytes[9] = 9;
+        assert!(matches!(Snapshot::from_bytes(&bytes), Err(SnapshotError::UnsupportedVersion(9))));
+    }
+}
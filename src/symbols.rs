@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+/// Label to address mapping produced by the assembler.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    by_name: BTreeMap<String, u16>,
+    by_addr: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a label. When several labels share an address, the first one
+    /// is used for reverse lookups.
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.by_name.insert(name.to_string(), addr);
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Symbols in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        let mut symbols: Vec<(&str, u16)> =
+            self.by_name.iter().map(|(name, &addr)| (name.as_str(), addr)).collect();
+        symbols.sort_by_key(|&(name, addr)| (addr, name));
+        symbols.into_iter()
+    }
+
+    /// Reads a `.sym` file as written by `lc3as` or `to_sym_string`.
+    /// Lines that are not symbol entries are ignored.
+    pub fn parse(text: &str) -> Self {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let Some(entry) = line.trim().strip_prefix("//") else {
+                continue;
+            };
+            let mut fields = entry.split_whitespace();
+            if let (Some(name), Some(addr), None) = (fields.next(), fields.next(), fields.next())
+                && let Ok(addr) = u16::from_str_radix(addr, 16)
+            {
+                table.insert(name, addr);
+            }
+        }
+        table
+    }
+
+    /// Renders the table in the `.sym` format written by `lc3as`.
+    pub fn to_sym_string(&self) -> String {
+        let mut out = String::from(
+            "// Symbol table\n\
+             // Scope level 0:\n\
+             //\tSymbol Name       Page Address\n\
+             //\t----------------  ------------\n",
+        );
+        for (name, addr) in self.iter() {
+            out.push_str(&format!("//\t{:<16}  {:04X}\n", name, addr));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sym_file_round_trip() {
+        let mut table = SymbolTable::new();
+        table.insert("START", 0x3000);
+        table.insert("MSG", 0x3010);
+
+        let parsed = SymbolTable::parse(&table.to_sym_string());
+
+        assert_eq!(parsed, table);
+        assert_eq!(parsed.name_at(0x3010), Some("MSG"));
+    }
+}
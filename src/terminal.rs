@@ -0,0 +1,116 @@
+use std::io;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, OnceLock};
+use libc::{c_int, termios, STDIN_FILENO};
+
+// The original terminal state lives in statics so the signal handler and
+// the panic hook can restore it without access to the guard.
+static ORIGINAL_TIO: OnceLock<termios> = OnceLock::new();
+static RAW_ACTIVE: AtomicBool = AtomicBool::new(false);
+static INSTALL_HOOKS: Once = Once::new();
+static SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static WATCH_SNAPSHOT: Once = Once::new();
+
+/// Puts the terminal in unbuffered, no-echo mode for as long as it lives.
+///
+/// The original state is restored on drop, before a panic message is
+/// printed, and on SIGINT/SIGTERM. When stdin is not a TTY (piped input)
+/// the session does nothing.
+pub struct TerminalSession {
+    raw: bool,
+}
+
+impl TerminalSession {
+    pub fn new() -> io::Result<Self> {
+        if unsafe { libc::isatty(STDIN_FILENO) } == 0 {
+            return Ok(TerminalSession { raw: false });
+        }
+
+        let mut term: termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(STDIN_FILENO, &mut term) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = *ORIGINAL_TIO.get_or_init(|| term);
+
+        INSTALL_HOOKS.call_once(install_hooks);
+
+        // disable ICANON and ECHO
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        if unsafe { libc::tcsetattr(STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        RAW_ACTIVE.store(true, Ordering::SeqCst);
+
+        Ok(TerminalSession { raw: true })
+    }
+
+    /// Whether the terminal was actually switched to raw mode.
+    pub fn is_raw(&self) -> bool {
+        self.raw
+    }
+}
+
+impl Drop for TerminalSession {
+    fn drop(&mut self) {
+        if self.raw {
+            restore_terminal();
+        }
+    }
+}
+
+// Only calls async-signal-safe functions, it also runs inside the signal handler.
+fn restore_terminal() {
+    if RAW_ACTIVE.swap(false, Ordering::SeqCst)
+        && let Some(original) = ORIGINAL_TIO.get()
+    {
+        unsafe {
+            libc::tcsetattr(STDIN_FILENO, libc::TCSANOW, original);
+        }
+    }
+}
+
+extern "C" fn handle_signal(signal: c_int) {
+    restore_terminal();
+    // Re-raise with the default action so the exit status stays correct.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+fn install_hooks() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        previous(info);
+    }));
+
+    let handler = handle_signal as extern "C" fn(c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Makes Ctrl-\ (SIGQUIT) and SIGUSR1 request a snapshot instead of
+/// killing the process. Poll for requests with `snapshot_requested`.
+pub fn watch_snapshot_signals() {
+    WATCH_SNAPSHOT.call_once(|| {
+        let handler = request_snapshot as extern "C" fn(c_int) as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGQUIT, handler);
+            libc::signal(libc::SIGUSR1, handler);
+        }
+    });
+}
+
+/// Whether a snapshot was requested since the last call.
+pub fn snapshot_requested() -> bool {
+    SNAPSHOT_REQUESTED.load(Ordering::Relaxed) && SNAPSHOT_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+extern "C" fn request_snapshot(_signal: c_int) {
+    SNAPSHOT_REQUESTED.store(true, Ordering::SeqCst);
+}
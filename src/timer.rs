@@ -0,0 +1,167 @@
+use std::time::{Duration, Instant};
+use crate::device::{Device, DeviceContext};
+use crate::interrupt::{Interrupt, TIMER_PRIORITY, TIMER_VECTOR};
+use crate::vm::{MR_TCNT, MR_TCR, MR_TRR};
+
+pub const TCR_EXPIRED: u16 = 1 << 15; /* set on expiry, cleared by reading TCR */
+pub const TCR_INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const TCR_MILLIS: u16 = 1 << 1; /* count milliseconds instead of instructions */
+pub const TCR_ENABLE: u16 = 1 << 0;
+const TCR_WRITABLE: u16 = TCR_INTERRUPT_ENABLE | TCR_MILLIS | TCR_ENABLE;
+
+/// Programmable interval timer. While enabled, TCNT counts down from the
+/// value in TRR, once per step or per millisecond. When it reaches
+/// zero the expired bit is set, TCNT is reloaded, and with interrupts
+/// enabled the timer requests interrupt x81 until TCR is read.
+#[derive(Debug, Default)]
+pub struct Timer {
+    control: u16,
+    reload: u16,
+    count: u16,
+    last_tick: Option<Instant>, /* start of the millisecond being counted */
+    requesting: bool,           /* whether our interrupt request is pending */
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn count_down(&mut self, mut ticks: u64) {
+        while ticks > 0 {
+            if self.reload == 0 {
+                return; // nothing to count down from
+            }
+            if u64::from(self.count) > ticks {
+                self.count -= ticks as u16;
+                return;
+            }
+            ticks -= u64::from(self.count);
+            self.control |= TCR_EXPIRED;
+            self.count = self.reload;
+        }
+    }
+
+    // Whole milliseconds since the last call, keeping the remainder.
+    fn elapsed_millis(&mut self) -> u64 {
+        let now = Instant::now();
+        let last = *self.last_tick.get_or_insert(now);
+        let millis = now.duration_since(last).as_millis() as u64;
+        self.last_tick = Some(last + Duration::from_millis(millis));
+        millis
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            MR_TCR => self.control,
+            MR_TRR => self.reload,
+            MR_TCNT => self.count,
+            _ => 0,
+        }
+    }
+
+    fn read(&mut self, addr: u16, _ctx: &mut DeviceContext) -> u16 {
+        let value = self.peek(addr);
+        if addr == MR_TCR {
+            self.control &= !TCR_EXPIRED;
+        }
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u16, _ctx: &mut DeviceContext) {
+        match addr {
+            MR_TCR => {
+                if self.control & TCR_ENABLE == 0 && value & TCR_ENABLE != 0 {
+                    self.count = self.reload;
+                    self.last_tick = None;
+                }
+                self.control = (self.control & TCR_EXPIRED) | (value & TCR_WRITABLE);
+            }
+            MR_TRR => self.reload = value,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut DeviceContext) {
+        if self.control & TCR_ENABLE != 0 {
+            let ticks = if self.control & TCR_MILLIS != 0 { self.elapsed_millis() } else { 1 };
+            self.count_down(ticks);
+        }
+        // Level triggered like the keyboard, but a request for the same
+        // vector raised by someone else is left alone.
+        if self.control & (TCR_EXPIRED | TCR_INTERRUPT_ENABLE) == TCR_EXPIRED | TCR_INTERRUPT_ENABLE {
+            ctx.interrupts.raise(Interrupt { vector: TIMER_VECTOR, priority: TIMER_PRIORITY });
+            self.requesting = true;
+        } else if self.requesting {
+            ctx.interrupts.clear(TIMER_VECTOR);
+            self.requesting = false;
+        }
+    }
+
+    // The wall clock restarts from the moment of the restore.
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.control, self.reload, self.count, self.requesting as u16]
+    }
+
+    fn restore_state(&mut self, state: &[u16]) {
+        if let [control, reload, count, requesting] = *state {
+            self.control = control;
+            self.reload = reload;
+            self.count = count;
+            self.requesting = requesting != 0;
+            self.last_tick = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::{StepOutcome, VM};
+
+    #[test]
+    fn test_instruction_timer_interrupts_on_expiry() {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(0x0181, 0x1000); // timer handler
+        for addr in 0x3000..0x3010 {
+            vm.mem_write(addr, 0x0000); // NOP
+        }
+        vm.mem_write(MR_TRR, 3);
+        vm.mem_write(MR_TCR, TCR_INTERRUPT_ENABLE | TCR_ENABLE);
+        vm.turn_on();
+
+        // The third tick expires the timer, before a third instruction runs.
+        for _ in 0..2 {
+            assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        }
+        assert_eq!(vm.peek(MR_TCR) & TCR_EXPIRED, 0);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Interrupted(TIMER_VECTOR));
+        assert_eq!(vm.get_pc(), 0x1000);
+
+        // Reading TCR acknowledges the expiry and withdraws the request.
+        assert_ne!(vm.mem_read(MR_TCR) & TCR_EXPIRED, 0);
+        assert_eq!(vm.peek(MR_TCR) & TCR_EXPIRED, 0);
+        assert_eq!(vm.peek(MR_TCNT), 3);
+    }
+
+    #[test]
+    fn test_millisecond_timer_counts_wall_clock() {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(MR_TRR, 1000);
+        vm.mem_write(MR_TCR, TCR_MILLIS | TCR_ENABLE);
+        vm.turn_on();
+
+        vm.step().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        vm.step().unwrap();
+        let count = vm.peek(MR_TCNT);
+        assert!((1..=980).contains(&count), "count {}", count);
+    }
+}
@@ -0,0 +1,268 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Range, RangeInclusive};
+use crate::disassembler;
+use crate::number;
+use crate::vm::{ConditionFlags, Opcodes, Register};
+
+/// Magic and version at the start of a binary trace.
+pub const TRACE_MAGIC: &[u8; 8] = b"LC3TRACE";
+pub const TRACE_VERSION: u16 = 1;
+
+/// A state change made while executing one instruction. Instruction
+/// fetches and PC updates are not included, the next record's PC shows
+/// where execution went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    RegWrite { reg: u8, old: u16, new: u16 }, /* R0-R7, or 9 for COND */
+    MemRead { addr: u16, value: u16 },
+    MemWrite { addr: u16, old: u16, new: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("unknown trace format `{}` (expected text or binary)", s)),
+        }
+    }
+}
+
+/// Which retired instructions end up in the trace. Every set condition
+/// must hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub addresses: Option<RangeInclusive<u16>>, /* PC of the instruction */
+    pub window: Option<Range<u64>>,             /* 0-based retired instruction count */
+    pub opcodes: Option<Vec<Opcodes>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, index: u64, pc: u16, instruction: u16) -> bool {
+        self.addresses.as_ref().is_none_or(|range| range.contains(&pc))
+            && self.window.as_ref().is_none_or(|window| window.contains(&index))
+            && self.opcodes.as_ref().is_none_or(|opcodes| {
+                Opcodes::try_from(instruction >> 12).is_ok_and(|op| opcodes.contains(&op))
+            })
+    }
+
+    /// Parses `x3000-x30FF` (inclusive).
+    pub fn parse_addresses(text: &str) -> Result<RangeInclusive<u16>, String> {
+        let (start, end) = text.split_once('-').ok_or_else(|| format!("expected <start>-<end>, got `{}`", text))?;
+        match (number::parse_word(start), number::parse_word(end)) {
+            (Some(start), Some(end)) if start <= end => Ok(start..=end),
+            _ => Err(format!("invalid address range `{}`", text)),
+        }
+    }
+
+    /// Parses `first:last` instruction counts (inclusive); either side may
+    /// be left out.
+    pub fn parse_window(text: &str) -> Result<Range<u64>, String> {
+        let (first, last) = text.split_once(':').ok_or_else(|| format!("expected <first>:<last>, got `{}`", text))?;
+        let bound = |part: &str, default| if part.is_empty() { Some(default) } else { number::parse_number(part) };
+        match (bound(first, 0), bound(last, u64::MAX - 1)) {
+            (Some(first), Some(last)) if first <= last => Ok(first..last + 1),
+            _ => Err(format!("invalid instruction window `{}`", text)),
+        }
+    }
+
+    /// Parses a comma separated list of opcode names, e.g. `LD,ST,TRAP`.
+    pub fn parse_opcodes(text: &str) -> Result<Vec<Opcodes>, String> {
+        text.split(',')
+            .map(|name| {
+                (0..16)
+                    .filter_map(|code| Opcodes::try_from(code).ok())
+                    .find(|op| op.name().eq_ignore_ascii_case(name.trim()))
+                    .ok_or_else(|| format!("unknown opcode `{}`", name))
+            })
+            .collect()
+    }
+}
+
+/// Writes one record per retired instruction that passes the filter.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    retired: u64,
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        let mut tracer = Tracer { out: Box::new(out), format, filter, retired: 0 };
+        if format == TraceFormat::Binary {
+            tracer.out.write_all(TRACE_MAGIC)?;
+            tracer.out.write_all(&TRACE_VERSION.to_be_bytes())?;
+        }
+        Ok(tracer)
+    }
+
+    /// Traces to a buffered file.
+    pub fn to_file(path: &str, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        Tracer::new(BufWriter::new(File::create(path)?), format, filter)
+    }
+
+    /// Number of instructions seen so far, traced or not.
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    pub fn record(&mut self, pc: u16, instruction: u16, effects: &[Effect]) -> io::Result<()> {
+        let index = self.retired;
+        self.retired += 1;
+        if !self.filter.matches(index, pc, instruction) {
+            return Ok(());
+        }
+        match self.format {
+            TraceFormat::Text => self.write_text(index, pc, instruction, effects),
+            TraceFormat::Binary => self.write_binary(index, pc, instruction, effects),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    // `      12 x3001 x1261  ADD R1, R1, #1            R1 x0001->x0002 COND Z->P`
+    fn write_text(&mut self, index: u64, pc: u16, instruction: u16, effects: &[Effect]) -> io::Result<()> {
+        let text = disassembler::disassemble(pc, instruction, None);
+        let mut line = format!("{:>8} x{:04X} x{:04X}  {:<24}", index, pc, instruction, text);
+        for effect in effects {
+            line += &match *effect {
+                Effect::RegWrite { reg, old, new } if reg == Register::COND as u8 => {
+                    format!(" COND {}->{}", cond_name(old), cond_name(new))
+                }
+                Effect::RegWrite { reg, old, new } => format!(" R{} x{:04X}->x{:04X}", reg, old, new),
+                Effect::MemRead { addr, value } => format!(" [x{:04X}]=x{:04X}", addr, value),
+                Effect::MemWrite { addr, old, new } => format!(" [x{:04X}] x{:04X}->x{:04X}", addr, old, new),
+            };
+        }
+        writeln!(self.out, "{}", line.trim_end())
+    }
+
+    // Big-endian: index u64, pc u16, instruction u16, effect count u16,
+    // then per effect a kind byte (0 register, 1 read, 2 write) and its
+    // operands as u16s (the register number as a u8).
+    fn write_binary(&mut self, index: u64, pc: u16, instruction: u16, effects: &[Effect]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(14 + effects.len() * 7);
+        record.extend_from_slice(&index.to_be_bytes());
+        record.extend_from_slice(&pc.to_be_bytes());
+        record.extend_from_slice(&instruction.to_be_bytes());
+        record.extend_from_slice(&(effects.len() as u16).to_be_bytes());
+        for effect in effects {
+            match *effect {
+                Effect::RegWrite { reg, old, new } => {
+                    record.extend_from_slice(&[0, reg]);
+                    put_words(&mut record, &[old, new]);
+                }
+                Effect::MemRead { addr, value } => {
+                    record.push(1);
+                    put_words(&mut record, &[addr, value]);
+                }
+                Effect::MemWrite { addr, old, new } => {
+                    record.push(2);
+                    put_words(&mut record, &[addr, old, new]);
+                }
+            }
+        }
+        self.out.write_all(&record)
+    }
+}
+
+fn put_words(out: &mut Vec<u8>, words: &[u16]) {
+    for word in words {
+        out.extend_from_slice(&word.to_be_bytes());
+    }
+}
+
+fn cond_name(cond: u16) -> &'static str {
+    ConditionFlags::try_from(cond).map_or("?", |flag| flag.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::console::BufferConsole;
+    use crate::interrupt::{Exception, ExceptionPolicy};
+    use crate::vm::VM;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn traced_run(filter: TraceFilter) -> String {
+        let out = Shared::default();
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(0x3000, 0x5020); // AND R0, R0, #0
+        vm.mem_write(0x3001, 0x1023); // ADD R0, R0, #3
+        vm.mem_write(0x3002, 0x3002); // ST R0, #2
+        vm.mem_write(0x3003, 0x2201); // LD R1, #1
+        vm.mem_write(0x3004, 0xF025); // HALT
+        vm.set_tracer(Some(Tracer::new(out.clone(), TraceFormat::Text, filter).unwrap()));
+        vm.run().unwrap();
+        String::from_utf8(out.0.borrow().clone()).unwrap()
+    }
+
+    #[test]
+    fn test_text_trace_shows_effects() {
+        let trace = traced_run(TraceFilter::default());
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with("AND R0, R0, #0           R0 x0000->x0000"), "{}", lines[0]);
+        assert!(lines[1].ends_with("R0 x0000->x0003 COND Z->P"));
+        assert!(lines[2].ends_with("[x3005] x0000->x0003"));
+        assert!(lines[3].ends_with("[x3005]=x0003 R1 x0000->x0003"));
+    }
+
+    #[test]
+    fn test_exception_entry_shows_stack_switch() {
+        let out = Shared::default();
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(0x3000, 0x8000); // RTI, privileged
+        vm.set_exception_policy(Exception::PrivilegeViolation, ExceptionPolicy::Vector);
+        vm.set_tracer(Some(Tracer::new(out.clone(), TraceFormat::Text, TraceFilter::default()).unwrap()));
+        vm.turn_on();
+        vm.step().unwrap();
+        let trace = String::from_utf8(out.0.borrow().clone()).unwrap();
+        assert!(trace.contains("R6 x0000->x3000 [x2FFF] x0000->x8002 [x2FFE] x0000->x3001 R6 x3000->x2FFE"), "{}", trace);
+    }
+
+    #[test]
+    fn test_filters() {
+        let filter = TraceFilter {
+            opcodes: Some(TraceFilter::parse_opcodes("st,ld").unwrap()),
+            ..TraceFilter::default()
+        };
+        assert_eq!(traced_run(filter).lines().count(), 2);
+
+        let filter = TraceFilter { window: Some(TraceFilter::parse_window("1:2").unwrap()), ..TraceFilter::default() };
+        let trace = traced_run(filter);
+        assert!(trace.starts_with("       1 x3001"));
+        assert_eq!(trace.lines().count(), 2);
+
+        let addresses = TraceFilter::parse_addresses("x3003-x3004").unwrap();
+        assert_eq!(addresses, 0x3003..=0x3004);
+        assert_eq!(traced_run(TraceFilter { addresses: Some(addresses), ..TraceFilter::default() }).lines().count(), 2);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+use crate::callstack::Frame;
+
+/// A store made by one step, with the value it overwrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub addr: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// Machine state from just before one step, plus the stores it made.
+/// Putting the registers back and undoing the stores in reverse order
+/// rewinds the step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    pub index: u64,           /* 0-based number of the step */
+    pub registers: [u16; 10], /* R0-R7, PC, COND */
+    pub psr: u16,
+    pub saved_usp: u16,
+    pub saved_ssp: u16,
+    pub running: bool,
+    pub writes: Vec<MemoryWrite>,
+    pub frames: Option<Vec<Frame>>, /* call stack, when the step could change it */
+}
+
+impl UndoRecord {
+    /// Address of the instruction the step executed (or the PC that was
+    /// interrupted).
+    pub fn pc(&self) -> u16 {
+        self.registers[8]
+    }
+}
+
+/// The most recent steps, oldest first. Once full, the oldest record is
+/// dropped for every new one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoLog {
+    records: VecDeque<UndoRecord>,
+    capacity: usize,
+    steps: u64,
+}
+
+impl UndoLog {
+    pub fn new(capacity: usize) -> Self {
+        UndoLog { records: VecDeque::new(), capacity, steps: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Number of steps recorded so far, including those already dropped.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Adds a record, numbering it after the previous one.
+    pub fn push(&mut self, mut record: UndoRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        record.index = self.steps;
+        self.steps += 1;
+        self.records.push_back(record);
+    }
+
+    /// Removes the newest record, the next step to undo.
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        let record = self.records.pop_back()?;
+        self.steps = record.index;
+        Some(record)
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &UndoRecord> {
+        self.records.iter()
+    }
+
+    /// The newest recorded step that stored to `addr`, and that store.
+    pub fn last_write_to(&self, addr: u16) -> Option<(&UndoRecord, &MemoryWrite)> {
+        self.records
+            .iter()
+            .rev()
+            .find_map(|record| record.writes.iter().rev().find(|write| write.addr == addr).map(|write| (record, write)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::{StepOutcome, VM};
+
+    fn counting_vm() -> VM {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(0x3000, 0x1021); // LOOP ADD R0, R0, #1
+        vm.mem_write(0x3001, 0x3003); // ST R0, COUNT
+        vm.mem_write(0x3002, 0x0FFD); // BRnzp LOOP
+        vm.set_undo_log(Some(UndoLog::new(100)));
+        vm.turn_on();
+        vm
+    }
+
+    #[test]
+    fn test_step_back_rewinds_registers_and_memory() {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(0x3000, 0x1021); // ADD R0, R0, #1
+        vm.mem_write(0x3001, 0x3001); // ST R0, #1
+        vm.mem_write(0x3002, 0xF025); // HALT
+        vm.set_undo_log(Some(UndoLog::new(100)));
+        assert_eq!(vm.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(vm.peek(0x3003), 1);
+
+        assert!(vm.step_back()); // HALT
+        assert!(vm.is_running());
+        assert_eq!(vm.get_pc(), 0x3002);
+        assert!(vm.step_back()); // ST
+        assert_eq!(vm.peek(0x3003), 0);
+        assert!(vm.step_back()); // ADD
+        assert_eq!((vm.read_reg(0), vm.read_reg(9)), (0, 2));
+        assert!(!vm.step_back());
+
+        assert_eq!(vm.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(vm.peek(0x3003), 1);
+    }
+
+    #[test]
+    fn test_reverse_continue_and_last_write() {
+        let mut vm = counting_vm();
+        for _ in 0..9 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.peek(0x3005), 3);
+
+        let log = vm.undo_log().unwrap();
+        let (record, write) = log.last_write_to(0x3005).unwrap();
+        assert_eq!((record.index, record.pc()), (7, 0x3001));
+        assert_eq!((write.old, write.new), (2, 3));
+
+        assert_eq!(vm.reverse_continue(|pc| pc == 0x3001), 2);
+        assert_eq!((vm.get_pc(), vm.peek(0x3005)), (0x3001, 2));
+        assert_eq!(vm.undo_log().unwrap().steps(), 7);
+        assert_eq!(vm.reverse_continue(|_| false), 7);
+        assert_eq!(vm.read_reg(0), 0);
+    }
+
+    #[test]
+    fn test_log_is_bounded() {
+        let mut vm = counting_vm();
+        vm.set_undo_log(Some(UndoLog::new(4)));
+        for _ in 0..10 {
+            vm.step().unwrap();
+        }
+        let log = vm.undo_log().unwrap();
+        assert_eq!((log.len(), log.steps()), (4, 10));
+        assert_eq!(log.iter().next().unwrap().index, 6);
+    }
+}
@@ -0,0 +1,307 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::console::Console;
+use crate::device::{Device, DeviceContext};
+
+/// Video memory, one 15-bit RGB word per pixel in row-major order.
+pub const VIDEO_BASE: u16 = 0xC000;
+pub const VIDEO_WIDTH: usize = 128;
+pub const VIDEO_HEIGHT: usize = 124;
+pub const VIDEO_END: u16 = VIDEO_BASE + (VIDEO_WIDTH * VIDEO_HEIGHT - 1) as u16;
+
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+const TICKS_PER_CLOCK_CHECK: u32 = 1024; /* keeps Instant::now() off the hot path */
+
+/// Bitmapped display backed by `xC000-xFDFF`. Headless by default; it
+/// can also draw itself on the terminal and dump every frame to PNG.
+#[derive(Debug)]
+pub struct Framebuffer {
+    pixels: Vec<u16>,
+    dirty: bool, /* written since the last frame was emitted */
+    terminal: bool,
+    frame_dir: Option<PathBuf>,
+    frame_error: Option<(PathBuf, io::Error)>, /* the frame that failed to write */
+    frames: u32,
+    ticks: u32,
+    last_frame: Option<Instant>,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer {
+            pixels: vec![0; VIDEO_WIDTH * VIDEO_HEIGHT],
+            dirty: false,
+            terminal: false,
+            frame_dir: None,
+            frame_error: None,
+            frames: 0,
+            ticks: 0,
+            last_frame: None,
+        }
+    }
+
+    /// Redraw the picture on the console whenever it changes.
+    pub fn set_terminal(&mut self, on: bool) {
+        self.terminal = on;
+    }
+
+    /// Write each frame to `dir/frame-NNNNN.png`.
+    pub fn set_frame_dir(&mut self, dir: Option<PathBuf>) {
+        self.frame_dir = dir;
+    }
+
+    /// The frame file that failed to write, if any. Dumping stops at the
+    /// first failure.
+    pub fn take_error(&mut self) -> Option<(PathBuf, io::Error)> {
+        self.frame_error.take()
+    }
+
+    /// Raw 15-bit pixel words, row-major.
+    pub fn pixels(&self) -> &[u16] {
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * VIDEO_WIDTH + x]
+    }
+
+    /// The picture as 8-bit RGB triples, row-major.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|&pixel| rgb(pixel)).collect()
+    }
+
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", VIDEO_WIDTH, VIDEO_HEIGHT).into_bytes();
+        out.extend(self.to_rgb());
+        out
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        encode_png(VIDEO_WIDTH as u32, VIDEO_HEIGHT as u32, &self.to_rgb())
+    }
+
+    /// Saves the picture as PPM if `path` ends in `.ppm`, PNG otherwise.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let bytes = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ppm") => self.to_ppm(),
+            _ => self.to_png(),
+        };
+        fs::write(path, bytes)
+    }
+
+    /// The picture drawn with upper half blocks, two pixel rows per text
+    /// line, using 24-bit ANSI colours. Starts at the top-left corner.
+    pub fn render_ansi(&self) -> String {
+        let mut out = String::from("\x1b[H");
+        for y in (0..VIDEO_HEIGHT).step_by(2) {
+            let mut colours = None;
+            for x in 0..VIDEO_WIDTH {
+                let cell = (rgb(self.pixel(x, y)), rgb(self.pixel(x, y + 1)));
+                if colours != Some(cell) {
+                    let ([r, g, b], [br, bg, bb]) = cell;
+                    let _ = write!(out, "\x1b[38;2;{};{};{};48;2;{};{};{}m", r, g, b, br, bg, bb);
+                    colours = Some(cell);
+                }
+                out.push('▀');
+            }
+            out.push_str("\x1b[0m\r\n");
+        }
+        out
+    }
+
+    /// Emits the last frame if it changed since the previous one, e.g.
+    /// once the program has halted.
+    pub fn flush(&mut self, console: &mut dyn Console) {
+        if self.dirty {
+            self.emit_frame(console);
+        }
+    }
+
+    fn emit_frame(&mut self, console: &mut dyn Console) {
+        self.dirty = false;
+        self.frames += 1;
+        if self.terminal {
+            let _ = console.write_bytes(self.render_ansi().as_bytes()).and_then(|_| console.flush());
+        }
+        if let Some(dir) = &self.frame_dir {
+            let path = dir.join(format!("frame-{:05}.png", self.frames));
+            if let Err(e) = fs::write(&path, self.to_png()) {
+                // Don't fail the program over a full disk, just stop dumping.
+                self.frame_error = Some((path, e));
+                self.frame_dir = None;
+            }
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &str {
+        "video"
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        self.pixels[addr.wrapping_sub(VIDEO_BASE) as usize]
+    }
+
+    fn write(&mut self, addr: u16, value: u16, _ctx: &mut DeviceContext) {
+        self.pixels[addr.wrapping_sub(VIDEO_BASE) as usize] = value & 0x7FFF;
+        self.dirty = true;
+    }
+
+    // Frames go out at most every FRAME_INTERVAL, and only after a change.
+    fn tick(&mut self, ctx: &mut DeviceContext) {
+        if !self.dirty || !(self.terminal || self.frame_dir.is_some()) {
+            return;
+        }
+        self.ticks += 1;
+        if self.ticks < TICKS_PER_CLOCK_CHECK && self.last_frame.is_some() {
+            return;
+        }
+        self.ticks = 0;
+        let now = Instant::now();
+        if self.last_frame.is_none_or(|last| now.duration_since(last) >= FRAME_INTERVAL) {
+            self.last_frame = Some(now);
+            self.emit_frame(ctx.console);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        self.pixels.clone()
+    }
+
+    fn restore_state(&mut self, state: &[u16]) {
+        if state.len() == self.pixels.len() {
+            self.pixels.copy_from_slice(state);
+            self.dirty = true;
+        }
+    }
+}
+
+// xRRRRRGGGGGBBBBB, each channel widened to 8 bits.
+fn rgb(pixel: u16) -> [u8; 3] {
+    let widen = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [widen((pixel >> 10) & 0x1F), widen((pixel >> 5) & 0x1F), widen(pixel & 0x1F)]
+}
+
+// Minimal PNG writer: 8-bit RGB, no filtering, stored (uncompressed)
+// deflate blocks.
+fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let row = width as usize * 3;
+    let mut raw = Vec::with_capacity((row + 1) * height as usize);
+    for line in rgb.chunks(row) {
+        raw.push(0); // filter type: none
+        raw.extend_from_slice(line);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8); // BFINAL, BTYPE=00
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // depth 8, RGB, deflate, no filter, no interlace
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_program_draws_into_framebuffer() {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.attach_device(VIDEO_BASE..=VIDEO_END, Framebuffer::new()).unwrap();
+        vm.mem_write(0x3000, 0x2203); // LD R1, COLOUR
+        vm.mem_write(0x3001, 0xB201); // STI R1, ADDR
+        vm.mem_write(0x3002, 0xF025); // HALT
+        vm.mem_write(0x3003, 0xC081); // ADDR: pixel (1, 1)
+        vm.mem_write(0x3004, 0x7C00); // COLOUR: pure red
+        vm.run().unwrap();
+
+        let video = vm.devices().get::<Framebuffer>().unwrap();
+        assert_eq!(video.pixel(1, 1), 0x7C00);
+        assert_eq!(vm.peek(0xC081), 0x7C00);
+        assert_eq!(&video.to_rgb()[(VIDEO_WIDTH + 1) * 3..][..3], &[255, 0, 0]);
+    }
+
+    #[test]
+    fn test_frame_write_failure_is_kept() {
+        let mut video = Framebuffer::new();
+        video.set_frame_dir(Some(PathBuf::from("/nonexistent/frames")));
+        video.pixels[0] = 0x7FFF;
+        video.dirty = true;
+        video.flush(&mut BufferConsole::new(b""));
+
+        let (path, _) = video.take_error().unwrap();
+        assert_eq!(path, Path::new("/nonexistent/frames/frame-00001.png"));
+        assert!(video.take_error().is_none());
+        assert!(video.frame_dir.is_none());
+    }
+
+    #[test]
+    fn test_png_and_ppm_encoding() {
+        let video = Framebuffer::new();
+        let ppm = video.to_ppm();
+        assert!(ppm.starts_with(b"P6\n128 124\n255\n"));
+        assert_eq!(ppm.len(), 15 + VIDEO_WIDTH * VIDEO_HEIGHT * 3);
+
+        let png = video.to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
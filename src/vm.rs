@@ -1,6 +1,18 @@
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io;
 use thiserror::Error;
+use std::ops::RangeInclusive;
+use crate::callstack::{StackTrace, CallStack};
+use crate::console::{Console, TerminalConsole};
+use crate::device::{Device, DeviceBus, DeviceContext, DeviceError, Display, Keyboard};
+use crate::timer::Timer;
+use crate::interrupt::{Exception, ExceptionPolicy, Interrupt, InterruptController, INTERRUPT_VECTOR_TABLE};
+use crate::limits::{RunLimit, RunLimits};
+use crate::loader::{Image, LoadError, LoadMap, LoadedImage, OverlapPolicy};
+use crate::profile::Profiler;
+use crate::snapshot::{hash_words, Snapshot, SnapshotError, HASH_SEED};
+use crate::trace::{Effect, Tracer};
+use crate::undo::{MemoryWrite, UndoLog, UndoRecord};
+use crate::watch::{Access, WatchHit, Watchpoints};
 
 #[derive(Error, Debug)]
 pub enum VMError {
@@ -12,24 +24,35 @@ pub enum VMError {
     FlushFailed,
     #[error("Memory out of bounds")]
     MemoryOutOfBounds,
-    #[error("Invalid opcode")]
-    InvalidOpcode,
+    #[error("Invalid opcode in instruction {instruction:#06x} at PC {pc:#06x}")]
+    InvalidOpcode { pc: u16, instruction: u16 },
+    #[error("Reserved opcode in instruction {instruction:#06x} at PC {pc:#06x}")]
+    ReservedOpcode { pc: u16, instruction: u16 },
+    #[error("Unknown trap vector {vector:#04x} in instruction {instruction:#06x} at PC {pc:#06x}")]
+    UnknownTrap { pc: u16, instruction: u16, vector: u16 },
+    #[error("RTI executed in user mode, instruction {instruction:#06x} at PC {pc:#06x}")]
+    PrivilegeViolation { pc: u16, instruction: u16 },
+    #[error("Console IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{error}\nBacktrace:\n{stack}")]
+    Fault { error: Box<VMError>, stack: StackTrace }, /* an error raised while the call stack was tracked */
 }
 
-// impl fmt::Display for VMError {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         match self {
-//             VMError::RegisterIndexOutOfBounds(_) => write!(f, "Register Index out of bounds"),
-//             VMError::PcOutOfBounds(_) => write!(f, "Out of bounds PC should fit in 16 bits"),
-//             VMError::FlushFailed => write!(f, "Memory out of bounds"),
-//             VMError::InvalidOpcode => write!(f, "Invalid opcode"),
-//             VMError::MemoryOutOfBounds => write!(f, "Memory access out of bounds"),
-//         }
-//     }
-// }
+impl VMError {
+    /// The LC-3 exception this fault corresponds to, if any.
+    pub fn exception(&self) -> Option<Exception> {
+        match self {
+            VMError::PrivilegeViolation { .. } => Some(Exception::PrivilegeViolation),
+            VMError::ReservedOpcode { .. } | VMError::InvalidOpcode { .. } => Some(Exception::IllegalOpcode),
+            VMError::Fault { error, .. } => error.exception(),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 #[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Register
 {
     R0 = 0,
@@ -45,11 +68,96 @@ pub enum Register
     COUNT = 10
 }
 
-const MR_KBSR: u16 = 0xFE00; // keyboard status
-const MR_KBDR: u16 = 0xFE02; // keyboard data
+pub const MR_KBSR: u16 = 0xFE00; // keyboard status
+pub const MR_KBDR: u16 = 0xFE02; // keyboard data
+pub const MR_DSR: u16 = 0xFE04; // display status
+pub const MR_DDR: u16 = 0xFE06; // display data
+pub const MR_TCR: u16 = 0xFE08; // timer control and status
+pub const MR_TRR: u16 = 0xFE0A; // timer reload
+pub const MR_TCNT: u16 = 0xFE0C; // timer count
+pub const MR_MCR: u16 = 0xFFFE; // machine control
+const MCR_CLOCK_ENABLE: u16 = 1 << 15;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
-enum TrapCodes {
+#[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Opcodes
+{
+    BR = 0, /* branch */
+    ADD = 1,    /* add  */
+    LD = 2,     /* load */
+    ST = 3,     /* store */
+    JSR = 4,    /* jump register */
+    AND = 5,    /* bitwise and */
+    LDR = 6,    /* load register */
+    STR = 7,    /* store register */
+    RTI = 8,    /* return from interrupt */
+    NOT = 9,    /* bitwise not */
+    LDI = 10,    /* load indirect */
+    STI = 11,    /* store indirect */
+    JMP = 12,    /* jump */
+    RES = 13,    /* reserved (unused) */
+    LEA = 14,    /* load effective address */
+    TRAP = 15   /* execute trap */
+}
+
+impl Opcodes {
+    /// Mnemonic of the opcode, e.g. `LDR`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Opcodes::BR => "BR",
+            Opcodes::ADD => "ADD",
+            Opcodes::LD => "LD",
+            Opcodes::ST => "ST",
+            Opcodes::JSR => "JSR",
+            Opcodes::AND => "AND",
+            Opcodes::LDR => "LDR",
+            Opcodes::STR => "STR",
+            Opcodes::RTI => "RTI",
+            Opcodes::NOT => "NOT",
+            Opcodes::LDI => "LDI",
+            Opcodes::STI => "STI",
+            Opcodes::JMP => "JMP",
+            Opcodes::RES => "RES",
+            Opcodes::LEA => "LEA",
+            Opcodes::TRAP => "TRAP",
+        }
+    }
+}
+
+// Decoding conversions hand back the rejected value so the caller can
+// build a `VMError` with the faulting PC and instruction.
+impl TryFrom<u16> for Opcodes {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Opcodes::BR),
+            1 => Ok(Opcodes::ADD),
+            2 => Ok(Opcodes::LD),
+            3 => Ok(Opcodes::ST),
+            4 => Ok(Opcodes::JSR),
+            5 => Ok(Opcodes::AND),
+            6 => Ok(Opcodes::LDR),
+            7 => Ok(Opcodes::STR),
+            8 => Ok(Opcodes::RTI),
+            9 => Ok(Opcodes::NOT),
+            10 => Ok(Opcodes::LDI),
+            11 => Ok(Opcodes::STI),
+            12 => Ok(Opcodes::JMP),
+            13 => Ok(Opcodes::RES),
+            14 => Ok(Opcodes::LEA),
+            15 => Ok(Opcodes::TRAP),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum TrapCodes {
     GETC = 0x20,  /* get character from keyboard, not echoed onto the terminal */
     OUT = 0x21,   /* output a character */
     PUTS = 0x22,  /* output a word string */
@@ -58,63 +166,183 @@ enum TrapCodes {
     HALT = 0x25   /* halt the program */
 }
 
-impl From<u16> for TrapCodes {
-    fn from(value: u16) -> Self {
+impl TrapCodes {
+    /// Assembler alias for the trap, e.g. `PUTS`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrapCodes::GETC => "GETC",
+            TrapCodes::OUT => "OUT",
+            TrapCodes::PUTS => "PUTS",
+            TrapCodes::IN => "IN",
+            TrapCodes::PUTSP => "PUTSP",
+            TrapCodes::HALT => "HALT",
+        }
+    }
+}
+
+impl TryFrom<u16> for TrapCodes {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            0x20 => TrapCodes::GETC,
-            0x21 => TrapCodes::OUT,
-            0x22 => TrapCodes::PUTS,
-            0x23 => TrapCodes::IN,
-            0x24 => TrapCodes::PUTSP,
-            0x25 => TrapCodes::HALT,
-            _ => panic!("Invalid opcode {}", value),
+            0x20 => Ok(TrapCodes::GETC),
+            0x21 => Ok(TrapCodes::OUT),
+            0x22 => Ok(TrapCodes::PUTS),
+            0x23 => Ok(TrapCodes::IN),
+            0x24 => Ok(TrapCodes::PUTSP),
+            0x25 => Ok(TrapCodes::HALT),
+            _ => Err(value),
         }
     }
 }
 
 const PC_START: u16 = 0x3000;
+const SSP_START: u16 = 0x3000; /* supervisor stack grows down from here */
+
+const PSR_USER: u16 = 1 << 15;         /* privilege bit, set in user mode */
+const PSR_PRIORITY_MASK: u16 = 0x0700; /* priority level, bits 10-8 */
+const PSR_COND_MASK: u16 = 0x0007;     /* N, Z, P */
 const MEMORY_SIZE: usize = 2_usize.pow(16);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
-enum ConditionFlags
+pub enum ConditionFlags
 {
     FlPOS = 1 << 0, /* P */
     FlZRO = 1 << 1, /* Z */
     FlNEG = 1 << 2, /* N */
 }
 
-impl From<u16> for ConditionFlags {
-    fn from(value: u16) -> Self {
+impl ConditionFlags {
+    /// Single-letter name used by debuggers: `N`, `Z` or `P`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConditionFlags::FlPOS => "P",
+            ConditionFlags::FlZRO => "Z",
+            ConditionFlags::FlNEG => "N",
+        }
+    }
+}
+
+impl TryFrom<u16> for ConditionFlags {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            1 => ConditionFlags::FlPOS,
-            2 => ConditionFlags::FlZRO,
-            4 => ConditionFlags::FlNEG,
-            _ => panic!("Invalid conditional flag"),
+            1 => Ok(ConditionFlags::FlPOS),
+            2 => Ok(ConditionFlags::FlZRO),
+            4 => Ok(ConditionFlags::FlNEG),
+            _ => Err(value),
         }
     }
 }
 
 fn sign_extend(mut x: u16, bit_count: usize) -> u16 {
     if ((x >> (bit_count - 1)) & 1) != 0 { // If sign bit is 1 (neg number)
-        x = x | (0xFFFF << bit_count); // fill with 1's
+        x |= 0xFFFF << bit_count; // fill with 1's
     }
     x
 }
 
+/// What happened when the VM executed a single instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Executed,      /* instruction retired, machine keeps running */
+    Trapped(u16),  /* a TRAP routine ran, carries the trap vector */
+    Interrupted(u8), /* entered an interrupt handler, no instruction ran */
+    Exception(u8),   /* the instruction faulted into an exception handler */
+    Halted,        /* the machine is stopped */
+    Watchpoint(WatchHit), /* the instruction retired and set off a watchpoint */
+    LimitReached(RunLimit), /* `run_with_limits` gave up, the machine is still running */
+}
+
+/// Number of recently executed PCs the VM remembers, see `recent_pcs`.
+pub const HISTORY_LEN: usize = 16;
+
+/// How TRAP instructions are serviced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrapMode {
+    #[default]
+    Native, /* GETC/OUT/PUTS/IN/PUTSP/HALT are implemented in Rust */
+    Memory, /* PC is loaded from the trap vector table, like the hardware */
+}
+
+impl std::str::FromStr for TrapMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(TrapMode::Native),
+            "memory" => Ok(TrapMode::Memory),
+            _ => Err(format!("unknown trap mode `{}` (expected native or memory)", s)),
+        }
+    }
+}
 
 pub struct VM {
     reg: [u16; 10],
     mem: [u16; MEMORY_SIZE],
     running: bool,
+    console: Box<dyn Console>,
+    psr: u16, /* privilege and priority bits, COND lives in `reg` */
+    saved_usp: u16,
+    saved_ssp: u16,
+    interrupts: InterruptController,
+    exception_policies: [ExceptionPolicy; 2],
+    trap_mode: TrapMode,
+    devices: DeviceBus,
+    program_hash: u64,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    effects: Vec<Effect>, /* changes made by the current step, while recording */
+    undo: Option<UndoLog>,
+    calls: Option<CallStack>,
+    watchpoints: Watchpoints,
+    watch_hit: Option<WatchHit>, /* first hit of the current step */
+    current_pc: u16,             /* instruction being executed, for watchpoint conditions */
+    history: [Option<u16>; HISTORY_LEN], /* ring of recently fetched PCs */
+    history_next: usize,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::with_console(TerminalConsole::new())
+    }
+
+    pub fn with_console(console: impl Console + 'static) -> Self {
         let mut vm = VM {
             reg: [0; 10],
             mem: [0; MEMORY_SIZE], 
             running: false,
+            console: Box::new(console),
+            psr: PSR_USER,
+            saved_usp: 0,
+            saved_ssp: SSP_START,
+            interrupts: InterruptController::new(),
+            exception_policies: [ExceptionPolicy::default(); 2],
+            trap_mode: TrapMode::default(),
+            devices: DeviceBus::new(),
+            program_hash: HASH_SEED,
+            tracer: None,
+            profiler: None,
+            effects: Vec::new(),
+            undo: None,
+            calls: None,
+            watchpoints: Watchpoints::new(),
+            watch_hit: None,
+            current_pc: 0,
+            history: [None; HISTORY_LEN],
+            history_next: 0,
         };
+        vm.attach_device(MR_KBSR..=MR_KBDR, Keyboard::new()).expect("keyboard fits an empty bus");
+        vm.attach_device(MR_DSR..=MR_DDR, Display::new()).expect("display fits an empty bus");
+        vm.attach_device(MR_TCR..=MR_TCNT, Timer::new()).expect("timer fits an empty bus");
 
         //Setup
         vm.set_reg(Register::PC as usize, PC_START);
@@ -122,35 +350,103 @@ impl VM {
         /* 0x3000 is the default */
             
         vm.reg[Register::COND as usize] = ConditionFlags::FlZRO as u16;
-        //println!("{}", vm.reg[9]);
-        // println!("{}", vm.reg[..10]);
         vm
     }
 
-     pub fn read_image(&mut self, path: &str) -> io::Result<()> {
-        let mut file = File::open(path)?;
-        self.read_image_file(&mut file)
+    pub fn read_image(&mut self, path: &str) -> Result<LoadedImage, LoadError> {
+        let image = Image::read(path)?;
+        self.load_image(&image)
     }
 
-    fn read_image_file(&mut self,file: &mut File) -> io::Result<()> {
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-    
-        let origin = u16::from_be_bytes([buf[0], buf[1]]);
+    /// Copies an image into memory at its origin. Images that run past
+    /// xFFFF are refused and nothing is written.
+    pub fn load_image(&mut self, image: &Image) -> Result<LoadedImage, LoadError> {
+        let origin = image.origin as usize;
+        let Some(memory) = self.mem.get_mut(origin..origin + image.words.len()) else {
+            return Err(LoadError::TooLarge { name: image.name.clone(), origin: image.origin, len: image.words.len() });
+        };
+        memory.copy_from_slice(&image.words);
+        self.program_hash = hash_words(hash_words(self.program_hash, &[image.origin]), &image.words);
+        Ok(LoadedImage { name: image.name.clone(), origin: image.origin, len: image.words.len() })
+    }
 
-    
-        for (i, chunk) in buf[2..].chunks_exact(2).enumerate() {
-            self.mem[origin.wrapping_add(i as u16) as usize] = u16::from_be_bytes([chunk[0], chunk[1]]);
+    /// Loads every image in order. Overlapping images are handled according
+    /// to `policy`; with `OverlapPolicy::Error` nothing is written past the
+    /// image that would overlap.
+    pub fn load_images<P: AsRef<str>>(&mut self, paths: &[P], policy: OverlapPolicy) -> Result<LoadMap, LoadError> {
+        let mut map = LoadMap::default();
+        for path in paths {
+            let image = Image::read(path.as_ref())?;
+            map.add(&image, policy)?;
+            self.load_image(&image)?;
         }
+        Ok(map)
+    }
+
+    /// Hash of every image loaded so far, in load order.
+    pub fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+
+    /// Captures registers, memory, device state and the run state.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.reg,
+            psr: self.psr,
+            saved_usp: self.saved_usp,
+            saved_ssp: self.saved_ssp,
+            running: self.running,
+            trap_mode: self.trap_mode,
+            exception_policies: self.exception_policies,
+            pending_interrupts: self.interrupts.pending().to_vec(),
+            memory: self.mem.to_vec(),
+            devices: self.devices.save_states(),
+            program_hash: self.program_hash,
+        }
+    }
 
-        // for (i, &value) in self.mem.iter().enumerate() {
-        //     if value != 0 {
-        //         println!("mem[0x{:04X}] = 0x{:04X}", i, value);
-        //     }
-        // }
+    /// Puts the machine back in the state of `snapshot`. Every device in
+    /// the snapshot must be attached and memory must be complete; nothing
+    /// is changed otherwise. Undo, call stack and recent-PC history start
+    /// over.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let attached = |name: &String| self.devices.iter().any(|(_, device)| device.name() == name);
+        if let Some((name, _)) = snapshot.devices.iter().find(|(name, _)| !attached(name)) {
+            return Err(SnapshotError::MissingDevice(name.clone()));
+        }
+        if snapshot.memory.len() != MEMORY_SIZE {
+            return Err(SnapshotError::MemorySize(snapshot.memory.len()));
+        }
+        self.devices.restore_states(&snapshot.devices).map_err(SnapshotError::MissingDevice)?;
+
+        self.reg = snapshot.registers;
+        self.psr = snapshot.psr;
+        self.saved_usp = snapshot.saved_usp;
+        self.saved_ssp = snapshot.saved_ssp;
+        self.running = snapshot.running;
+        self.trap_mode = snapshot.trap_mode;
+        self.exception_policies = snapshot.exception_policies;
+        self.interrupts = InterruptController::new();
+        for &interrupt in &snapshot.pending_interrupts {
+            self.interrupts.raise(interrupt);
+        }
+        self.mem.copy_from_slice(&snapshot.memory);
+        self.program_hash = snapshot.program_hash;
+        if let Some(undo) = &mut self.undo {
+            undo.clear();
+        }
+        if let Some(calls) = &mut self.calls {
+            *calls = CallStack::new();
+        }
+        self.history = [None; HISTORY_LEN];
+        self.history_next = 0;
         Ok(())
     }
 
+    pub fn set_console(&mut self, console: impl Console + 'static) {
+        self.console = Box::new(console);
+    }
+
     pub fn advance_pc(&mut self){
         self.reg[Register::PC as usize] = self.reg[Register::PC as usize].wrapping_add(1);
     }
@@ -160,36 +456,297 @@ impl VM {
     }
 
     pub fn mem_read(&mut self, addr: u16) -> u16 {
-        if addr == MR_KBSR.into() {
-            let mut buffer = [0; 1];
-            io::stdin().read_exact(&mut buffer).unwrap();
-            if buffer[0] != 0 {
-                self.mem[MR_KBSR as usize] = 1 << 15;
-                self.mem[MR_KBDR as usize] = buffer[0] as u16;
-            } else {
-                self.mem[MR_KBSR as usize] = 0;
+        let value = match self.devices.at_mut(addr) {
+            Some(device) => {
+                let mut ctx = DeviceContext { console: self.console.as_mut(), interrupts: &mut self.interrupts };
+                device.read(addr, &mut ctx)
             }
+            None => self.mem[addr as usize],
+        };
+        if self.recording() {
+            self.effects.push(Effect::MemRead { addr, value });
         }
-        self.mem[addr as usize]
-        
+        if !self.watchpoints.is_empty() {
+            self.watch(Access::Read, addr, value, value);
+        }
+        value
+    }
+
+    /// Reads memory without triggering device side effects.
+    pub fn peek(&self, addr: u16) -> u16 {
+        match self.devices.at(addr) {
+            Some(device) => device.peek(addr),
+            None => self.mem[addr as usize],
+        }
+    }
+
+    /// Writes memory without device side effects, watchpoints or undo
+    /// records. Device registers can't be written this way, so returns
+    /// false for them.
+    pub fn poke(&mut self, addr: u16, val: u16) -> bool {
+        if self.devices.at(addr).is_some() {
+            return false;
+        }
+        self.mem[addr as usize] = val;
+        true
     }
 
     pub fn mem_write(&mut self, addr: u16, val: u16) {
+        if self.recording() {
+            self.effects.push(Effect::MemWrite { addr, old: self.peek(addr), new: val });
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(Access::Write, addr, self.peek(addr), val);
+        }
+        if let Some(device) = self.devices.at_mut(addr) {
+            let mut ctx = DeviceContext { console: self.console.as_mut(), interrupts: &mut self.interrupts };
+            device.write(addr, val, &mut ctx);
+            return;
+        }
         self.mem[addr as usize] = val;
+        if addr == MR_MCR && val & MCR_CLOCK_ENABLE == 0 {
+            self.running = false;
+        }
+    }
+
+    /// Attaches a memory-mapped device to `range`. Loads and stores to
+    /// those addresses go to the device instead of RAM.
+    pub fn attach_device(&mut self, range: RangeInclusive<u16>, device: impl Device) -> Result<(), DeviceError> {
+        self.devices.attach(range, device)
+    }
+
+    pub fn devices(&self) -> &DeviceBus {
+        &self.devices
+    }
+
+    pub fn devices_mut(&mut self) -> &mut DeviceBus {
+        &mut self.devices
+    }
+
+    fn tick_devices(&mut self) {
+        let mut ctx = DeviceContext { console: self.console.as_mut(), interrupts: &mut self.interrupts };
+        self.devices.tick(&mut ctx);
     }
 
     pub fn read_reg(&mut self, id: usize) -> u16 {
         self.reg.get(id).copied().expect("Warning: value must be set")
     }
 
+    /// Starts the clock, setting bit 15 of the MCR.
     pub fn turn_on(&mut self) {
         self.running = true;
+        self.mem[MR_MCR as usize] |= MCR_CLOCK_ENABLE;
+    }
+
+    // Stops the clock the same way a program would, by clearing MCR bit 15.
+    fn halt(&mut self) {
+        let mcr = self.mem[MR_MCR as usize];
+        self.mem_write(MR_MCR, mcr & !MCR_CLOCK_ENABLE);
     }
 
     pub fn is_running(&mut self) -> bool {
         self.running
     }
 
+    /// Fetches, decodes and executes the instruction at PC.
+    pub fn step(&mut self) -> Result<StepOutcome, VMError> {
+        if !self.running {
+            return Ok(StepOutcome::Halted);
+        }
+        let checkpoint = self.undo.is_some().then(|| self.checkpoint());
+        let outcome = self.step_once();
+        if let Some(undo) = &mut self.undo
+            && let Some(mut record) = checkpoint
+        {
+            record.writes = self
+                .effects
+                .iter()
+                .filter_map(|effect| match *effect {
+                    Effect::MemWrite { addr, old, new } => Some(MemoryWrite { addr, old, new }),
+                    _ => None,
+                })
+                .collect();
+            undo.push(record);
+        }
+        outcome.map_err(|error| match &self.calls {
+            Some(calls) => VMError::Fault { error: Box::new(error), stack: calls.backtrace(self.current_pc) },
+            None => error,
+        })
+    }
+
+    fn step_once(&mut self) -> Result<StepOutcome, VMError> {
+        self.effects.clear();
+        self.watch_hit = None;
+        self.current_pc = self.get_pc();
+
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.initiate_interrupt(interrupt.vector, interrupt.priority);
+            if let Some(hit) = self.watch_hit.take() {
+                return Ok(StepOutcome::Watchpoint(hit));
+            }
+            return Ok(StepOutcome::Interrupted(interrupt.vector));
+        }
+
+        let curr_pc = self.get_pc();
+        self.history[self.history_next] = Some(curr_pc);
+        self.history_next = (self.history_next + 1) % HISTORY_LEN;
+        let instruction: u16 = self.mem_read(curr_pc);
+        let operation = Opcodes::try_from(instruction >> 12)
+            .map_err(|_| VMError::InvalidOpcode { pc: curr_pc, instruction })?;
+
+        self.advance_pc();
+        // the fetch is not an effect of the instruction, nor a watched read
+        self.effects.clear();
+        self.watch_hit = None;
+
+        let outcome = match self.execute(operation, instruction) {
+            Err(error) => self.raise_exception(error),
+            // A store to the MCR stopped the clock.
+            Ok(StepOutcome::Executed) if !self.running => Ok(StepOutcome::Halted),
+            outcome => outcome,
+        };
+        if let Some(calls) = &mut self.calls
+            && !matches!(outcome, Ok(StepOutcome::Exception(_)) | Err(_))
+        {
+            let next_pc = self.reg[Register::PC as usize];
+            calls.retire(curr_pc, instruction, next_pc, self.trap_mode == TrapMode::Memory);
+        }
+        let outcome = match (outcome, self.watch_hit.take()) {
+            (Ok(outcome), Some(hit)) if outcome != StepOutcome::Halted => Ok(StepOutcome::Watchpoint(hit)),
+            (outcome, _) => outcome,
+        };
+        if let Some(tracer) = &mut self.tracer
+            && outcome.is_ok()
+        {
+            tracer.record(curr_pc, instruction, &self.effects)?;
+        }
+        if let Some(profiler) = &mut self.profiler
+            && outcome.is_ok()
+        {
+            profiler.record(curr_pc, instruction, &self.effects);
+        }
+        outcome
+    }
+
+    fn execute(&mut self, operation: Opcodes, instruction: u16) -> Result<StepOutcome, VMError> {
+        match operation {
+            Opcodes::ADD => self.add(instruction)?,
+            Opcodes::AND => self.and(instruction)?,
+            Opcodes::NOT => self.not(instruction)?,
+            Opcodes::BR => self.branch(instruction)?,
+            Opcodes::JMP => self.jmp(instruction)?,
+            Opcodes::JSR => self.jump(instruction)?,
+            Opcodes::LD => self.load(instruction)?,
+            Opcodes::LDI => self.ldi(instruction)?,
+            Opcodes::LDR => self.ldr(instruction)?,
+            Opcodes::LEA => self.lea(instruction)?,
+            Opcodes::ST => self.store(instruction)?,
+            Opcodes::STI => self.store_indirect(instruction)?,
+            Opcodes::STR => self.store_register(instruction)?,
+            Opcodes::TRAP => {
+                let return_addr = self.get_pc();
+                match self.trap_mode {
+                    TrapMode::Native => {
+                        // An unknown vector fails before R7 is overwritten.
+                        self.trap_code(instruction)?;
+                        self.write_reg(Register::R7 as usize, return_addr);
+                        self.execute_trap_routine(instruction)?
+                    }
+                    TrapMode::Memory => {
+                        self.write_reg(Register::R7 as usize, return_addr);
+                        let routine = self.mem_read(instruction & 0xFF);
+                        self.set_pc(routine);
+                    }
+                }
+                if !self.running {
+                    return Ok(StepOutcome::Halted);
+                }
+                return Ok(StepOutcome::Trapped(instruction & 0xFF));
+            }
+            Opcodes::RES => self.reserved(instruction)?,
+            Opcodes::RTI => self.rti(instruction)?,
+        }
+
+        Ok(StepOutcome::Executed)
+    }
+
+    // Faults that map to an LC-3 exception either go to the host as an
+    // error or vector through the table, depending on the policy.
+    fn raise_exception(&mut self, error: VMError) -> Result<StepOutcome, VMError> {
+        let Some(exception) = error.exception() else {
+            return Err(error);
+        };
+        match self.exception_policy(exception) {
+            ExceptionPolicy::ReturnError => Err(error),
+            ExceptionPolicy::Vector => {
+                let priority = self.priority();
+                self.initiate_interrupt(exception.vector(), priority);
+                Ok(StepOutcome::Exception(exception.vector()))
+            }
+        }
+    }
+
+    pub fn trap_mode(&self) -> TrapMode {
+        self.trap_mode
+    }
+
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.trap_mode = mode;
+    }
+
+    pub fn exception_policy(&self, exception: Exception) -> ExceptionPolicy {
+        self.exception_policies[exception as usize]
+    }
+
+    pub fn set_exception_policy(&mut self, exception: Exception, policy: ExceptionPolicy) {
+        self.exception_policies[exception as usize] = policy;
+    }
+
+    /// Turns the machine on and steps until it halts, faults or sets off a
+    /// watchpoint.
+    pub fn run(&mut self) -> Result<StepOutcome, VMError> {
+        self.run_with_limits(RunLimits::default())
+    }
+
+    /// Like `run`, but also stops with `StepOutcome::LimitReached` once
+    /// the instruction budget or the timeout runs out.
+    pub fn run_with_limits(&mut self, limits: RunLimits) -> Result<StepOutcome, VMError> {
+        self.run_with_hook(limits, |_| {})
+    }
+
+    /// Like `run_with_limits`, calling `after_step` after every step that
+    /// doesn't end the run, e.g. to save a snapshot on request.
+    pub fn run_with_hook(
+        &mut self,
+        limits: RunLimits,
+        mut after_step: impl FnMut(&mut VM),
+    ) -> Result<StepOutcome, VMError> {
+        self.turn_on();
+        let mut budget = limits.start();
+        if let Some(limit) = budget.used_up() {
+            return Ok(StepOutcome::LimitReached(limit));
+        }
+        loop {
+            let outcome = self.step()?;
+            match outcome {
+                StepOutcome::Halted | StepOutcome::Watchpoint(_) | StepOutcome::LimitReached(_) => return Ok(outcome),
+                StepOutcome::Executed
+                | StepOutcome::Trapped(_)
+                | StepOutcome::Interrupted(_)
+                | StepOutcome::Exception(_) => {}
+            }
+            if let Some(limit) = budget.charge(outcome) {
+                return Ok(StepOutcome::LimitReached(limit));
+            }
+            after_step(self);
+        }
+    }
+
+    /// The last `HISTORY_LEN` PCs fetched from, oldest first.
+    pub fn recent_pcs(&self) -> Vec<u16> {
+        (0..HISTORY_LEN).filter_map(|i| self.history[(self.history_next + i) % HISTORY_LEN]).collect()
+    }
+
     pub fn set_pc(&mut self, pc: u16) {
         self.reg[Register::PC as usize] = pc;
     }
@@ -198,32 +755,251 @@ impl VM {
         self.reg[id] = value
     }
 
+    // Register writes made by instructions, recorded for the trace. PC
+    // updates are not recorded.
+    fn write_reg(&mut self, id: usize, value: u16) {
+        if self.recording() && id != Register::PC as usize {
+            self.effects.push(Effect::RegWrite { reg: id as u8, old: self.reg[id], new: value });
+        }
+        self.reg[id] = value;
+    }
+
+    fn recording(&self) -> bool {
+        self.tracer.is_some() || self.profiler.is_some() || self.undo.is_some()
+    }
+
+    /// Starts or stops tracing retired instructions. Returns the previous
+    /// tracer, e.g. to flush it.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    /// Starts or stops profiling retired instructions. Returns the previous
+    /// profiler, e.g. to write its report.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Starts or stops keeping a shadow call stack. While it is kept,
+    /// errors from `step` and `run` come wrapped in `VMError::Fault` with a
+    /// backtrace.
+    pub fn set_call_stack(&mut self, calls: Option<CallStack>) -> Option<CallStack> {
+        std::mem::replace(&mut self.calls, calls)
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.calls.as_ref()
+    }
+
+    pub fn call_stack_mut(&mut self) -> Option<&mut CallStack> {
+        self.calls.as_mut()
+    }
+
+    /// Starts or stops recording steps so they can be undone. Returns the
+    /// previous log.
+    pub fn set_undo_log(&mut self, undo: Option<UndoLog>) -> Option<UndoLog> {
+        std::mem::replace(&mut self.undo, undo)
+    }
+
+    pub fn undo_log(&self) -> Option<&UndoLog> {
+        self.undo.as_ref()
+    }
+
+    /// Watchpoints are checked on every load and store made by
+    /// instructions, TRAP routines and interrupt entry, but not on
+    /// instruction fetches or `peek`.
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    fn watch(&mut self, access: Access, addr: u16, old: u16, value: u16) {
+        if self.watch_hit.is_none() {
+            self.watch_hit = self.watchpoints.check(access, addr, old, value, self.current_pc);
+        }
+    }
+
+    fn checkpoint(&self) -> UndoRecord {
+        UndoRecord {
+            index: 0,
+            registers: self.reg,
+            psr: self.psr,
+            saved_usp: self.saved_usp,
+            saved_ssp: self.saved_ssp,
+            running: self.running,
+            writes: Vec::new(),
+            frames: self
+                .calls
+                .as_ref()
+                .filter(|_| CallStack::affected_by(self.peek(self.reg[Register::PC as usize])))
+                .map(CallStack::save_frames),
+        }
+    }
+
+    /// Undoes the most recent recorded step. Stores to device registers,
+    /// console output and consumed input are not taken back. Returns false
+    /// when there is nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(record) = self.undo.as_mut().and_then(|undo| undo.pop()) else {
+            return false;
+        };
+        for write in record.writes.iter().rev() {
+            if self.devices.at(write.addr).is_none() {
+                self.mem[write.addr as usize] = write.old;
+            }
+        }
+        self.reg = record.registers;
+        self.psr = record.psr;
+        self.saved_usp = record.saved_usp;
+        self.saved_ssp = record.saved_ssp;
+        self.running = record.running;
+        if let (Some(calls), Some(frames)) = (&mut self.calls, record.frames) {
+            calls.restore_frames(frames);
+        }
+        true
+    }
+
+    /// Steps back at least once, then until `is_breakpoint(pc)` or the log
+    /// runs out. Returns how many steps were undone.
+    pub fn reverse_continue(&mut self, is_breakpoint: impl Fn(u16) -> bool) -> usize {
+        let mut undone = 0;
+        while self.step_back() {
+            undone += 1;
+            if is_breakpoint(self.get_pc()) {
+                break;
+            }
+        }
+        undone
+    }
+
+    /// Processor status register: privilege (bit 15), priority level
+    /// (bits 10-8) and the condition codes (bits 2-0).
+    pub fn get_psr(&self) -> u16 {
+        self.psr | (self.reg[Register::COND as usize] & PSR_COND_MASK)
+    }
+
+    /// Writes the PSR. The stack pointers are not switched, that is up to
+    /// the caller (RTI, interrupt entry).
+    pub fn set_psr(&mut self, psr: u16) {
+        self.psr = psr & (PSR_USER | PSR_PRIORITY_MASK);
+        self.reg[Register::COND as usize] = psr & PSR_COND_MASK;
+    }
+
+    pub fn is_supervisor(&self) -> bool {
+        self.psr & PSR_USER == 0
+    }
+
+    pub fn priority(&self) -> u8 {
+        ((self.psr & PSR_PRIORITY_MASK) >> 8) as u8
+    }
+
+    /// Requests an interrupt through `vector` at `priority` (0-7). It is
+    /// taken between instructions once its priority exceeds the PSR's.
+    pub fn raise_interrupt(&mut self, vector: u8, priority: u8) {
+        self.interrupts.raise(Interrupt { vector, priority });
+    }
+
+    pub fn interrupts(&self) -> &InterruptController {
+        &self.interrupts
+    }
+
+    pub fn interrupts_mut(&mut self) -> &mut InterruptController {
+        &mut self.interrupts
+    }
+
+    // Devices get to raise or withdraw their requests before each
+    // instruction, e.g. the level-triggered keyboard interrupt.
+    fn pending_interrupt(&mut self) -> Option<Interrupt> {
+        self.tick_devices();
+        let priority = self.priority();
+        self.interrupts.take_above(priority)
+    }
+
+    // Switches to the supervisor stack, pushes PSR and PC, and jumps
+    // through the interrupt vector table.
+    fn initiate_interrupt(&mut self, vector: u8, priority: u8) {
+        let psr = self.get_psr();
+        if !self.is_supervisor() {
+            self.saved_usp = self.reg[Register::R6 as usize];
+            self.write_reg(Register::R6 as usize, self.saved_ssp);
+        }
+
+        let pc = self.get_pc();
+        let sp = self.reg[Register::R6 as usize].wrapping_sub(2);
+        self.mem_write(sp.wrapping_add(1), psr);
+        self.mem_write(sp, pc);
+        self.write_reg(Register::R6 as usize, sp);
+
+        self.psr = ((priority as u16) << 8) & PSR_PRIORITY_MASK;
+        let handler = self.mem_read(INTERRUPT_VECTOR_TABLE + vector as u16);
+        self.set_pc(handler);
+    }
+
+    /// User stack pointer saved while in supervisor mode.
+    pub fn saved_usp(&self) -> u16 {
+        self.saved_usp
+    }
+
+    /// Supervisor stack pointer saved while in user mode.
+    pub fn saved_ssp(&self) -> u16 {
+        self.saved_ssp
+    }
+
+    pub fn set_saved_ssp(&mut self, ssp: u16) {
+        self.saved_ssp = ssp;
+    }
+
+    pub fn set_saved_usp(&mut self, usp: u16) {
+        self.saved_usp = usp;
+    }
+
+    // PC of the instruction being executed; handlers run after the PC
+    // has been advanced past it.
+    fn instruction_pc(&self) -> u16 {
+        self.reg[Register::PC as usize].wrapping_sub(1)
+    }
+
     fn update_flags(&mut self, r: usize) {
-        // println!("I will update flag {}", r);
-        if self.reg[r] == 0 {
-            self.reg[Register::COND as usize] = ConditionFlags::FlZRO as u16;
+        let cond = if self.reg[r] == 0 {
+            ConditionFlags::FlZRO
         } else if self.reg[r] >> 15 != 0 {
-            self.reg[Register::COND as usize] = ConditionFlags::FlNEG as u16;
+            ConditionFlags::FlNEG
         } else {
-            self.reg[Register::COND as usize] = ConditionFlags::FlPOS as u16;
+            ConditionFlags::FlPOS
+        } as u16;
+        // Only actual condition code changes are recorded.
+        if self.reg[Register::COND as usize] != cond {
+            self.write_reg(Register::COND as usize, cond);
         }
     }
 
-    pub fn add(&mut self, instruction: u16) {
+    pub fn add(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0 = ((instruction >> 9) & 0x7) as usize;
         let r1 = ((instruction >> 6) & 0x7) as usize;
         if (instruction >> 5) & 0x1 != 0 {
             let imm5 = sign_extend(instruction & 0x1F, 5);
-            self.reg[r0] = self.reg[r1].wrapping_add(imm5);
+            self.write_reg(r0, self.reg[r1].wrapping_add(imm5));
         } else {
             let r2= (instruction & 0x7) as usize;
-            self.reg[r0] = self.reg[r1].wrapping_add(self.reg[r2]);
+            self.write_reg(r0, self.reg[r1].wrapping_add(self.reg[r2]));
         }
     
         self.update_flags(r0);
+        Ok(())
     }
 
-    pub fn ldi(&mut self, instruction: u16) {
+    pub fn ldi(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0 = ((instruction >> 9) & 0x7) as usize;
         
         let pc_offset: u16 = sign_extend(instruction & 0x1FF, 9); // obtain the 9-bit offset sign-extended
@@ -231,167 +1007,220 @@ impl VM {
         let addr = self.mem_read(
             self.reg[Register::PC as usize].wrapping_add(pc_offset)
         );
-        self.reg[r0] = self.mem_read(addr);
+        let value = self.mem_read(addr);
+        self.write_reg(r0, value);
         self.update_flags(r0);
+        Ok(())
     }
 
-    pub fn and(&mut self, instruction: u16) {
+    pub fn and(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0 = ((instruction >> 9) & 0x7) as usize;
         let r1 = ((instruction >> 6) & 0x7) as usize;
         let imm_flag: u16 = (instruction >> 5) & 0x1;
     
         if imm_flag != 0 {
         let imm5 = sign_extend(instruction & 0x1F, 5);
-        self.reg[r0] = self.reg[r1] & imm5;
+        self.write_reg(r0, self.reg[r1] & imm5);
         } else {
             let r2 = (instruction & 0x7) as usize;
-            self.reg[r0] = self.reg[r1] & self.reg[r2];
+            self.write_reg(r0, self.reg[r1] & self.reg[r2]);
         }
     
         self.update_flags(r0);
+        Ok(())
     }
 
-    pub fn not(&mut self, instruction: u16) {
+    pub fn not(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0 = ((instruction >> 9) & 0x7) as usize;
         let r1 = ((instruction >> 6) & 0x7) as usize;
 
-        self.reg[r0] = !self.reg[r1];
+        self.write_reg(r0, !self.reg[r1]);
         self.update_flags(r0);
+        Ok(())
     }
 
-    pub fn branch(&mut self, instruction: u16){
+    pub fn branch(&mut self, instruction: u16) -> Result<(), VMError> {
         let pc_offset: u16 = sign_extend(instruction & 0x1FF, 9);
         let cond_flag: u16 = (instruction >> 9) & 0x7;
 
         if cond_flag & self.reg[Register::COND as usize] != 0 {
-            self.reg[Register::PC as usize] = self.reg[Register::PC as usize].wrapping_add(pc_offset);
+            self.set_pc(self.reg[Register::PC as usize].wrapping_add(pc_offset));
         }
+        Ok(())
     }
 
-    pub fn jmp(&mut self, instruction: u16) {
+    pub fn jmp(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0: u16 = (instruction >> 6) & 0x7;
         self.set_reg(Register::PC as usize, self.reg[r0 as usize]);
+        Ok(())
     }
 
-    pub fn jump(&mut self, instruction: u16) {
+    pub fn jump(&mut self, instruction: u16) -> Result<(), VMError> {
         let long_flag: u16 = (instruction >> 11) & 1;
-        self.reg[Register::R7 as usize] = self.reg[Register::PC as usize];
-
+        self.write_reg(Register::R7 as usize, self.reg[Register::PC as usize]);
         if long_flag != 0 {
             let long_offset: u16 = sign_extend(instruction & 0x7FF, 11);
-            self.reg[Register::PC as usize] = self.reg[Register::PC as usize].wrapping_add(long_offset); 
+            self.set_pc(self.reg[Register::PC as usize].wrapping_add(long_offset));
         } else {
             let r1 = ((instruction >> 6) & 0x7) as usize;
-            self.reg[Register::PC as usize] = self.reg[r1];
+            self.set_pc(self.reg[r1]);
         }
+        Ok(())
     }
 
-    pub fn load(&mut self, instruction: u16) {
+    pub fn load(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0 = ((instruction >> 9) & 0x7) as usize;
         let pc_offset: u16 = sign_extend(instruction & 0x1FF, 9);
-        self.reg[r0] = self.mem_read(self.reg[Register::PC as usize].wrapping_add(pc_offset));
+        let value = self.mem_read(self.reg[Register::PC as usize].wrapping_add(pc_offset));
+        self.write_reg(r0, value);
         self.update_flags(r0);
+        Ok(())
     }
 
-    pub fn ldr(&mut self, instruction: u16) {
+    pub fn ldr(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0  = ((instruction >> 9) & 0x7) as usize;
         let r1  = ((instruction >> 6) & 0x7) as usize;
         let offset = sign_extend(instruction & 0x3F, 6);
-        self.reg[r0] = self.mem_read(self.reg[r1].wrapping_add(offset));
+        let value = self.mem_read(self.reg[r1].wrapping_add(offset));
+        self.write_reg(r0, value);
         self.update_flags(r0);
+        Ok(())
     }
 
-    pub fn lea(&mut self, instruction: u16) { // load effective address
+    pub fn lea(&mut self, instruction: u16) -> Result<(), VMError> { // load effective address
         let r0: usize = ((instruction >> 9) & 0x7) as usize;
         let pc_offset: u16 = sign_extend(instruction & 0x1FF, 9);
-        self.reg[r0] = self.reg[Register::PC as usize] + pc_offset;
+        self.write_reg(r0, self.reg[Register::PC as usize].wrapping_add(pc_offset));
         self.update_flags(r0); 
+        Ok(())
     }
 
-    pub fn store(&mut self, instruction: u16) {
+    pub fn store(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0 = ((instruction >> 9) & 0x7) as usize;
         let pc_offset: u16 = sign_extend(instruction & 0x1FF, 9);
-        self.mem_write(self.reg[Register::PC as usize].wrapping_add(pc_offset), self.reg[r0])
+        self.mem_write(self.reg[Register::PC as usize].wrapping_add(pc_offset), self.reg[r0]);
+        Ok(())
     }
 
-    pub fn store_indirect(&mut self, instruction: u16) {
+    pub fn store_indirect(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0: usize = ((instruction >> 9) & 0x7) as usize;
         let pc_offset: u16 = sign_extend(instruction & 0x1FF, 9);
-        let indirect_addr = self.mem_read(self.reg[Register::PC as usize] + pc_offset);
+        let indirect_addr = self.mem_read(self.reg[Register::PC as usize].wrapping_add(pc_offset));
         self.mem_write(indirect_addr, self.reg[r0]);
+        Ok(())
     }
 
-    pub fn store_register(&mut self, instruction: u16) {
+    pub fn store_register(&mut self, instruction: u16) -> Result<(), VMError> {
         let r0: usize = ((instruction >> 9) & 0x7) as usize;
         let r1: usize = ((instruction >> 6) & 0x7) as usize;
         let offset: u16 = sign_extend(instruction & 0x3F, 6);
         self.mem_write(self.reg[r1].wrapping_add(offset), self.reg[r0]);
+        Ok(())
+    }
+
+    pub fn rti(&mut self, instruction: u16) -> Result<(), VMError> {
+        if !self.is_supervisor() {
+            return Err(VMError::PrivilegeViolation { pc: self.instruction_pc(), instruction });
+        }
+
+        let sp = self.reg[Register::R6 as usize];
+        let pc = self.mem_read(sp);
+        let psr = self.mem_read(sp.wrapping_add(1));
+        self.write_reg(Register::R6 as usize, sp.wrapping_add(2));
+        self.set_pc(pc);
+        self.set_psr(psr);
+
+        if !self.is_supervisor() {
+            self.saved_ssp = self.reg[Register::R6 as usize];
+            self.write_reg(Register::R6 as usize, self.saved_usp);
+        }
+        Ok(())
+    }
+
+    fn trap_code(&self, instruction: u16) -> Result<TrapCodes, VMError> {
+        TrapCodes::try_from(instruction & 0xFF)
+            .map_err(|vector| VMError::UnknownTrap { pc: self.instruction_pc(), instruction, vector })
     }
 
-    pub fn execute_trap_routine(&mut self, instruction: u16) {
-        //self.reg[Register::R7 as usize] = self.reg[Register::PC as usize];
-        //println!("TRAP instruction: {:04X}", instruction);
-        let operation = TrapCodes::from(instruction & 0xFF);
+    pub fn reserved(&mut self, instruction: u16) -> Result<(), VMError> {
+        Err(VMError::ReservedOpcode { pc: self.instruction_pc(), instruction })
+    }
+
+    pub fn execute_trap_routine(&mut self, instruction: u16) -> Result<(), VMError> {
+        let operation = self.trap_code(instruction)?;
         match operation {
             TrapCodes::HALT => {
-                self.running = false;
+                self.halt();
             }
             TrapCodes::PUTS => {
-                let mut i = self.reg[0] as u16;
-                while self.mem_read(i) != 0x0000 {
-                    let ch = self.mem_read(i) as u8;
-                    print!("{}", ch as char);
-                    //eprint!("{}", ch as char);
-                    i += 1;
+                let mut out = Vec::new();
+                let mut i = self.reg[0];
+                loop {
+                    let ch = self.mem_read(i);
+                    if ch == 0x0000 {
+                        break;
+                    }
+                    out.push(ch as u8);
+                    i = i.wrapping_add(1);
                 }
-                io::stdout()
-                    .flush()
-                    .unwrap_or_else(|_| panic!("{}", VMError::FlushFailed));
+                self.console.write_bytes(&out)?;
+                self.console.flush().map_err(|_| VMError::FlushFailed)?;
             }
             TrapCodes::OUT => {
-                let ch = self.reg[Register::R0 as usize] as u8 as char;
-                print!("{}", ch);
-                io::stdout().flush().unwrap();
+                let ch = self.reg[Register::R0 as usize] as u8;
+                self.console.write_bytes(&[ch])?;
+                self.console.flush().map_err(|_| VMError::FlushFailed)?;
             }
             TrapCodes::IN => {
-                    print!("Enter a character: ");
-                    io::stdout().flush().unwrap();
+                    self.console.write_bytes(b"Enter a character: ")?;
+                    self.console.flush().map_err(|_| VMError::FlushFailed)?;
 
-                    let mut buffer = [0u8; 1];
-                    io::stdin().read_exact(&mut buffer).unwrap();
+                    let c = self.console.read_byte()?;
 
-                    let c: u16 = buffer[0] as u16;
-
-                    io::stdout().write_all(&[c.try_into().unwrap()]).unwrap();
-                    io::stdout().flush().unwrap();
-
-                    self.reg[Register::R0 as usize] = c;
+                    self.console.write_bytes(&[c])?;
+                    self.console.flush().map_err(|_| VMError::FlushFailed)?;
 
+                    self.write_reg(Register::R0 as usize, c as u16);
                     self.update_flags(Register::R0 as usize);
             }
             TrapCodes::GETC => {
-                let mut buffer = [0; 1];
-                io::stdin().read_exact(&mut buffer).unwrap();
-                self.reg[0] = buffer[0] as u16;
+                let c = self.console.read_byte()?;
+                self.write_reg(Register::R0 as usize, c as u16);
                 self.update_flags(Register::R0 as usize);
             }
             TrapCodes::PUTSP => {
+                let mut out = Vec::new();
                 let mut i = self.reg[0];
-                while self.mem[i as usize] != 0x0000 {
-                    let ch = self.mem[i as usize];
+                loop {
+                    let ch = self.mem_read(i);
+                    if ch == 0x0000 {
+                        break;
+                    }
                     let (ch1, ch2) = (ch & 0xFF, ch >> 8);
-                    print!("{}", (ch1 as u8) as char);
-                    eprint!("{}", (ch1 as u8) as char);
+                    out.push(ch1 as u8);
                     if ch2 != 0x00 {
-                        print!("{}", (ch2 as u8) as char);
-                        eprint!("{}", (ch2 as u8) as char);
+                        out.push(ch2 as u8);
                     }
-                    i += 1;
+                    i = i.wrapping_add(1);
                 }
+                self.console.write_bytes(&out)?;
+                self.console.flush().map_err(|_| VMError::FlushFailed)?;
             }
 
         }
+        Ok(())
     }
 
 }
 
+#[cfg(test)]
+impl VM {
+    /// Assembles `source` into a fresh VM with no console input. Tests
+    /// turn on whatever tracking they need themselves.
+    pub(crate) fn from_source(source: &str) -> (VM, crate::symbols::SymbolTable) {
+        let assembly = crate::assembler::assemble(source).unwrap();
+        let mut vm = VM::with_console(crate::console::BufferConsole::new(b""));
+        vm.load_image(&Image { name: "test".to_string(), origin: assembly.origin, words: assembly.words }).unwrap();
+        (vm, assembly.symbols)
+    }
+}
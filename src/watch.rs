@@ -0,0 +1,275 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use crate::number;
+use crate::trace::TraceFilter;
+
+/// Which accesses a watchpoint fires on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatchKind {
+    Read,
+    #[default]
+    Write,
+    Change, /* a store that changes the value */
+}
+
+impl std::str::FromStr for WatchKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(WatchKind::Read),
+            "write" => Ok(WatchKind::Write),
+            "change" => Ok(WatchKind::Change),
+            _ => Err(format!("unknown watch kind `{}` (expected read, write or change)", s)),
+        }
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+        })
+    }
+}
+
+/// Extra test a watched access must pass. `value` is the value read or
+/// stored, `pc` the instruction making the access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchCondition {
+    ValueEq(u16),                 /* value == x7FFF */
+    ValueNe(u16),                 /* value != 0 */
+    PcIn(RangeInclusive<u16>),    /* pc in x3000-x30FF */
+    PcNotIn(RangeInclusive<u16>), /* pc not in x3000-x30FF */
+}
+
+impl WatchCondition {
+    pub fn holds(&self, value: u16, pc: u16) -> bool {
+        match self {
+            WatchCondition::ValueEq(expected) => value == *expected,
+            WatchCondition::ValueNe(expected) => value != *expected,
+            WatchCondition::PcIn(range) => range.contains(&pc),
+            WatchCondition::PcNotIn(range) => !range.contains(&pc),
+        }
+    }
+}
+
+impl std::str::FromStr for WatchCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let condition = match words.as_slice() {
+            ["value", "==", value] => number::parse_value(value).map(WatchCondition::ValueEq),
+            ["value", "!=", value] => number::parse_value(value).map(WatchCondition::ValueNe),
+            ["pc", "in", range] => TraceFilter::parse_addresses(range).ok().map(WatchCondition::PcIn),
+            ["pc", "not", "in", range] => TraceFilter::parse_addresses(range).ok().map(WatchCondition::PcNotIn),
+            _ => None,
+        };
+        condition.ok_or_else(|| {
+            format!("invalid condition `{}` (expected value ==|!= <n> or pc [not] in <start>-<end>)", s)
+        })
+    }
+}
+
+impl fmt::Display for WatchCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchCondition::ValueEq(value) => write!(f, "value == x{:04X}", value),
+            WatchCondition::ValueNe(value) => write!(f, "value != x{:04X}", value),
+            WatchCondition::PcIn(range) => write!(f, "pc in x{:04X}-x{:04X}", range.start(), range.end()),
+            WatchCondition::PcNotIn(range) => write!(f, "pc not in x{:04X}-x{:04X}", range.start(), range.end()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    pub condition: Option<WatchCondition>,
+}
+
+impl Watchpoint {
+    pub fn new(range: RangeInclusive<u16>, kind: WatchKind) -> Self {
+        Watchpoint { range, kind, condition: None }
+    }
+
+    pub fn when(mut self, condition: WatchCondition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    /// Parses `<addr>[-<end>] [read|write|change] [if <condition>]`, using
+    /// `resolve` for the addresses so callers can accept labels.
+    pub fn parse(text: &str, resolve: impl Fn(&str) -> Option<u16>) -> Result<Watchpoint, String> {
+        let (spec, condition) = match text.split_once(" if ") {
+            Some((spec, condition)) => (spec, Some(condition.parse()?)),
+            None => (text, None),
+        };
+        let mut words = spec.split_whitespace();
+        let target = words.next().ok_or("expected an address to watch")?;
+        let address = |text: &str| resolve(text).ok_or_else(|| format!("unknown address or label `{}`", text));
+        let range = match target.split_once('-') {
+            Some((start, end)) => address(start)?..=address(end)?,
+            None => address(target)?..=address(target)?,
+        };
+        if range.is_empty() {
+            return Err(format!("invalid address range `{}`", target));
+        }
+        let kind = words.next().map_or(Ok(WatchKind::default()), str::parse)?;
+        if let Some(extra) = words.next() {
+            return Err(format!("unexpected `{}`", extra));
+        }
+        Ok(Watchpoint { range, kind, condition })
+    }
+
+    fn triggers(&self, access: Access, addr: u16, old: u16, value: u16, pc: u16) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::Change => access == Access::Write && old != value,
+        };
+        kind_matches
+            && self.range.contains(&addr)
+            && self.condition.as_ref().is_none_or(|condition| condition.holds(value, pc))
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x{:04X}", self.range.start())?;
+        if self.range.start() != self.range.end() {
+            write!(f, "-x{:04X}", self.range.end())?;
+        }
+        write!(f, " {}", self.kind)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// The access that set off a watchpoint. The instruction has completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub pc: u16, /* instruction that made the access */
+    pub addr: u16,
+    pub access: Access,
+    pub old: u16,   /* value before a store, same as `value` for reads */
+    pub value: u16,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.access {
+            Access::Read => {
+                write!(f, "Watchpoint {}: read x{:04X} from x{:04X} at PC x{:04X}", self.id, self.value, self.addr, self.pc)
+            }
+            Access::Write => write!(
+                f,
+                "Watchpoint {}: store to x{:04X}, x{:04X} -> x{:04X}, at PC x{:04X}",
+                self.id, self.addr, self.old, self.value, self.pc
+            ),
+        }
+    }
+}
+
+/// Watchpoints by id. Ids start at 1 and are not reused.
+#[derive(Debug, Clone, Default)]
+pub struct Watchpoints {
+    entries: Vec<(usize, Watchpoint)>,
+    next_id: usize,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        self.next_id += 1;
+        self.entries.push((self.next_id, watchpoint));
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(entry, _)| *entry != id);
+        self.entries.len() != before
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.entries.iter().map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    /// The first watchpoint set off by an access, if any.
+    pub fn check(&self, access: Access, addr: u16, old: u16, value: u16, pc: u16) -> Option<WatchHit> {
+        self.entries
+            .iter()
+            .find(|(_, watchpoint)| watchpoint.triggers(access, addr, old, value, pc))
+            .map(|&(id, _)| WatchHit { id, pc, addr, access, old, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::vm::{StepOutcome, VM};
+
+    fn hex(text: &str) -> Option<u16> {
+        u16::from_str_radix(text.strip_prefix('x')?, 16).ok()
+    }
+
+    #[test]
+    fn test_parse_watchpoints() {
+        let watchpoint = Watchpoint::parse("x4000-x400F change if pc not in x3000-x3010", hex).unwrap();
+        assert_eq!(watchpoint.range, 0x4000..=0x400F);
+        assert_eq!(watchpoint.kind, WatchKind::Change);
+        assert_eq!(watchpoint.condition, Some(WatchCondition::PcNotIn(0x3000..=0x3010)));
+        assert_eq!(watchpoint.to_string(), "x4000-x400F change if pc not in x3000-x3010");
+
+        let watchpoint = Watchpoint::parse("x4000 if value == #-1", hex).unwrap();
+        assert_eq!(watchpoint.to_string(), "x4000 write if value == xFFFF");
+        assert!(Watchpoint::parse("x4000 sometimes", hex).is_err());
+        assert!(Watchpoint::parse("x4000 if value > 3", hex).is_err());
+    }
+
+    #[test]
+    fn test_overrun_stops_the_run() {
+        let mut vm = VM::with_console(BufferConsole::new(b""));
+        vm.mem_write(0x3000, 0xE206); // LEA R1, BUF
+        vm.mem_write(0x3001, 0x54A0); // AND R2, R2, #0
+        vm.mem_write(0x3002, 0x7440); // LOOP STR R2, R1, #0
+        vm.mem_write(0x3003, 0x1261); // ADD R1, R1, #1
+        vm.mem_write(0x3004, 0x14A1); // ADD R2, R2, #1
+        vm.mem_write(0x3005, 0x0FFC); // BRnzp LOOP
+        // BUF is two words at x3007, anything past it is an overrun
+        let id = vm.watchpoints_mut().add(Watchpoint::new(0x3009..=0x300F, WatchKind::Write));
+
+        let StepOutcome::Watchpoint(hit) = vm.run().unwrap() else { panic!("no watchpoint hit") };
+        assert_eq!(hit, WatchHit { id, pc: 0x3002, addr: 0x3009, access: Access::Write, old: 0, value: 2 });
+        assert_eq!(vm.get_pc(), 0x3003);
+
+        // Instruction fetches are not reads.
+        vm.watchpoints_mut().remove(id);
+        vm.watchpoints_mut().add(Watchpoint::new(0x3002..=0x3005, WatchKind::Read));
+        let id = vm.watchpoints_mut().add(Watchpoint::new(0x3007..=0x300F, WatchKind::Change).when(WatchCondition::ValueEq(5)));
+        let StepOutcome::Watchpoint(hit) = vm.run().unwrap() else { panic!("no watchpoint hit") };
+        assert_eq!((hit.id, hit.addr, hit.value), (id, 0x300C, 5));
+    }
+}